@@ -0,0 +1,32 @@
+use bevy::prelude::*;
+
+use crate::stage::ActiveStage;
+use crate::GameStates;
+
+fn spawn_game_soundtrack(mut commands: Commands,
+                         asset_server: Res<AssetServer>,
+                         stage: Res<ActiveStage>) {
+    let soundtrack = asset_server.load::<AudioSource>(&stage.0.music);
+    commands.spawn((
+        DespawnOnExit(GameStates::Game),
+        AudioPlayer(soundtrack),
+        PlaybackSettings::LOOP,
+    ));
+}
+
+fn spawn_game_over_sound(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let soundtrack = asset_server.load::<AudioSource>("sounds/game_over.ogg");
+    commands.spawn((
+        DespawnOnExit(GameStates::GameOver),
+        AudioPlayer(soundtrack),
+        PlaybackSettings::ONCE,
+    ));
+}
+
+pub struct AudioPlugin;
+impl Plugin for AudioPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(OnEnter(GameStates::Game), spawn_game_soundtrack);
+        app.add_systems(OnEnter(GameStates::GameOver), spawn_game_over_sound);
+    }
+}
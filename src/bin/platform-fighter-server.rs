@@ -0,0 +1,376 @@
+//! Hosts lobbies for online matches and relays packets between peers which
+//! can't reach each other directly.
+
+use platform_fighter::character::default_fighter;
+use platform_fighter::lobby::{
+    decode, encode, is_relayed, ClientMessage, LobbyMember, LobbyState, LobbySummary,
+    ServerMessage, DEFAULT_SERVER_ADDR, HEARTBEAT_SECS, LOBBY_SIZE,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{SocketAddr, UdpSocket};
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const USAGE: &str = "usage: platform-fighter-server [--bind ADDR]";
+/// Clients which weren't heard from for this many heartbeats are dropped.
+const TIMEOUT_HEARTBEATS: f32 = 10.;
+
+/// Member who just joined as `player`, with the character that player gets
+/// by default in local matches.
+fn new_member(player: u32) -> LobbyMember {
+    LobbyMember { fighter: default_fighter(player).0, palette: 0, ready: false }
+}
+
+struct Lobby {
+    state: LobbyState,
+    /// Address of each member, in the same order.
+    addrs: Vec<SocketAddr>,
+    /// Set from the start of a match until every member acknowledged it.
+    starting: Option<Starting>,
+}
+
+struct Starting {
+    seed: u64,
+    /// Whether each member acknowledged the start, in the same order as
+    /// the addresses.
+    acked: Vec<bool>,
+}
+
+struct Server {
+    socket: UdpSocket,
+    /// When each client was last heard from.
+    clients: HashMap<SocketAddr, Instant>,
+    lobbies: BTreeMap<u32, Lobby>,
+    next_id: u32,
+}
+
+impl Server {
+    fn new(socket: UdpSocket) -> Self {
+        Server { socket, clients: HashMap::new(), lobbies: BTreeMap::new(), next_id: 1 }
+    }
+
+    fn send(&self, to: SocketAddr, message: &ServerMessage) {
+        if let Err(e) = self.socket.send_to(&encode(message), to) {
+            eprintln!("could not send to {}: {}", to, e);
+        }
+    }
+
+    fn lobby_of(&self, addr: SocketAddr) -> Option<u32> {
+        self.lobbies.values()
+            .find(|lobby| lobby.addrs.contains(&addr))
+            .map(|lobby| lobby.state.id)
+    }
+
+    fn broadcast(&self, id: u32) {
+        let Some(lobby) = self.lobbies.get(&id) else {
+            return;
+        };
+        for (i, addr) in lobby.addrs.iter().enumerate() {
+            self.send(*addr, &ServerMessage::Lobby { lobby: lobby.state.clone(), player: i as u32 + 1 });
+        }
+    }
+
+    fn list_lobbies(&self, to: SocketAddr) {
+        let lobbies = self.lobbies.values()
+            .map(|lobby| LobbySummary {
+                id: lobby.state.id,
+                players: lobby.addrs.len(),
+                win_condition: lobby.state.win_condition,
+                stage: lobby.state.stage.clone(),
+            })
+            .collect();
+        self.send(to, &ServerMessage::Lobbies { lobbies });
+    }
+
+    fn leave(&mut self, addr: SocketAddr) {
+        let Some(id) = self.lobby_of(addr) else {
+            return;
+        };
+        let lobby = self.lobbies.get_mut(&id).unwrap();
+        let index = lobby.addrs.iter().position(|a| *a == addr).unwrap();
+        lobby.addrs.remove(index);
+        lobby.state.members.remove(index);
+        lobby.starting = None;
+        if lobby.addrs.is_empty() {
+            println!("lobby {} closed", id);
+            self.lobbies.remove(&id);
+        }
+        else {
+            self.broadcast(id);
+        }
+    }
+
+    fn start(&mut self, id: u32) {
+        let lobby = self.lobbies.get_mut(&id).unwrap();
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        println!("lobby {} starts with seed {}", id, seed);
+        lobby.starting = Some(Starting { seed, acked: vec![false; lobby.addrs.len()] });
+        for addr in lobby.addrs.clone() {
+            self.send_start(addr);
+        }
+    }
+
+    /// Sends `Start` to `addr` if its lobby is starting and it didn't
+    /// acknowledge that yet.
+    fn send_start(&self, addr: SocketAddr) {
+        let Some(lobby) = self.lobby_of(addr).and_then(|id| self.lobbies.get(&id)) else {
+            return;
+        };
+        let Some(starting) = &lobby.starting else {
+            return;
+        };
+        let index = lobby.addrs.iter().position(|a| *a == addr).unwrap();
+        if !starting.acked[index] {
+            let peer = lobby.addrs[(index + 1) % lobby.addrs.len()];
+            self.send(addr, &ServerMessage::Start { seed: starting.seed, player: index as u32 + 1, peer });
+        }
+    }
+
+    /// Once every member of the lobby acknowledged the start, their ready
+    /// flags are cleared for the next match.
+    fn acknowledge_start(&mut self, addr: SocketAddr) {
+        let Some(id) = self.lobby_of(addr) else {
+            return;
+        };
+        let lobby = self.lobbies.get_mut(&id).unwrap();
+        let Some(starting) = &mut lobby.starting else {
+            return;
+        };
+        let index = lobby.addrs.iter().position(|a| *a == addr).unwrap();
+        starting.acked[index] = true;
+        if starting.acked.iter().all(|acked| *acked) {
+            lobby.starting = None;
+            for member in &mut lobby.state.members {
+                member.ready = false;
+            }
+            self.broadcast(id);
+        }
+    }
+
+    fn handle(&mut self, from: SocketAddr, message: ClientMessage) {
+        match message {
+            ClientMessage::ListLobbies => { self.list_lobbies(from); }
+            ClientMessage::Host { win_condition, stage } => {
+                self.leave(from);
+                let id = self.next_id;
+                self.next_id += 1;
+                println!("{} hosts lobby {}", from, id);
+                self.lobbies.insert(id, Lobby {
+                    state: LobbyState {
+                        id,
+                        win_condition,
+                        stage,
+                        members: vec![new_member(1)],
+                    },
+                    addrs: vec![from],
+                    starting: None,
+                });
+                self.broadcast(id);
+            }
+            ClientMessage::Join { lobby: id } => {
+                if self.lobby_of(from) == Some(id) {
+                    return;
+                }
+                self.leave(from);
+                let Some(lobby) = self.lobbies.get_mut(&id) else {
+                    self.send(from, &ServerMessage::Error { message: format!("no lobby {}", id) });
+                    return;
+                };
+                if lobby.addrs.len() >= LOBBY_SIZE {
+                    self.send(from, &ServerMessage::Error { message: format!("lobby {} is full", id) });
+                    return;
+                }
+                println!("{} joins lobby {}", from, id);
+                lobby.addrs.push(from);
+                lobby.state.members.push(new_member(lobby.addrs.len() as u32));
+                self.broadcast(id);
+            }
+            ClientMessage::Leave => {
+                self.leave(from);
+                self.list_lobbies(from);
+            }
+            ClientMessage::Pick { fighter, palette, ready } => {
+                let Some(id) = self.lobby_of(from) else {
+                    return;
+                };
+                let lobby = self.lobbies.get_mut(&id).unwrap();
+                let index = lobby.addrs.iter().position(|a| *a == from).unwrap();
+                lobby.state.members[index] = LobbyMember { fighter, palette, ready };
+                let all_ready = lobby.addrs.len() == LOBBY_SIZE
+                    && lobby.state.members.iter().all(|member| member.ready)
+                    && lobby.starting.is_none();
+                if all_ready {
+                    self.start(id);
+                }
+                self.broadcast(id);
+            }
+            ClientMessage::Stage { stage } => {
+                let Some(id) = self.lobby_of(from) else {
+                    return;
+                };
+                let lobby = self.lobbies.get_mut(&id).unwrap();
+                if lobby.addrs[0] == from {
+                    lobby.state.stage = stage;
+                    self.broadcast(id);
+                }
+            }
+            // A client which still sends heartbeats didn't get `Start`.
+            ClientMessage::Heartbeat => { self.send_start(from); }
+            ClientMessage::Started => { self.acknowledge_start(from); }
+        }
+    }
+
+    /// Forwards a netcode packet to the other players of the sender's lobby.
+    fn relay(&self, from: SocketAddr, packet: &[u8]) {
+        let Some(lobby) = self.lobby_of(from).and_then(|id| self.lobbies.get(&id)) else {
+            return;
+        };
+        for addr in lobby.addrs.iter().filter(|addr| **addr != from) {
+            if let Err(e) = self.socket.send_to(packet, addr) {
+                eprintln!("could not relay to {}: {}", addr, e);
+            }
+        }
+    }
+
+    fn drop_silent_clients(&mut self) {
+        let timeout = Duration::from_secs_f32(HEARTBEAT_SECS * TIMEOUT_HEARTBEATS);
+        let silent: Vec<SocketAddr> = self.clients.iter()
+            .filter(|(_, last_seen)| last_seen.elapsed() > timeout)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in silent {
+            println!("{} timed out", addr);
+            self.clients.remove(&addr);
+            self.leave(addr);
+        }
+    }
+
+    /// Serves clients until `stop` is set, which is noticed within the read
+    /// timeout of the socket.
+    fn run(&mut self, stop: &AtomicBool) {
+        let mut buffer = [0; 2048];
+        while !stop.load(Ordering::Relaxed) {
+            match self.socket.recv_from(&mut buffer) {
+                Ok((len, from)) => {
+                    self.clients.insert(from, Instant::now());
+                    let bytes = &buffer[..len];
+                    if is_relayed(bytes) {
+                        // Only clients which started the match send netcode packets.
+                        self.acknowledge_start(from);
+                        self.relay(from, bytes);
+                    }
+                    else if let Some(message) = decode(bytes) {
+                        self.handle(from, message);
+                    }
+                }
+                // Timeouts, so silent clients are dropped even without traffic.
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {}
+                Err(e) => { eprintln!("could not receive: {}", e); }
+            }
+            self.drop_silent_clients();
+        }
+    }
+}
+
+fn main() {
+    let mut bind: SocketAddr = DEFAULT_SERVER_ADDR.parse().unwrap();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next().map(|v| v.parse())) {
+            ("--bind", Some(Ok(addr))) => { bind = addr; }
+            _ => {
+                eprintln!("{}", USAGE);
+                exit(2);
+            }
+        }
+    }
+    let socket = match UdpSocket::bind(bind) {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("could not bind to {}: {}", bind, e);
+            exit(1);
+        }
+    };
+    socket.set_read_timeout(Some(Duration::from_secs_f32(HEARTBEAT_SECS))).unwrap();
+    println!("listening on {}", bind);
+    Server::new(socket).run(&AtomicBool::new(false));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use platform_fighter::game_match::WinCondition;
+    use std::sync::Arc;
+
+    fn client() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        socket
+    }
+
+    fn send(socket: &UdpSocket, server: SocketAddr, message: ClientMessage) {
+        socket.send_to(&encode(&message), server).unwrap();
+    }
+
+    /// Skips messages until one matches `wanted`, panics on a timeout.
+    fn receive(socket: &UdpSocket, wanted: impl Fn(&ServerMessage) -> bool) -> ServerMessage {
+        let mut buffer = [0; 4096];
+        loop {
+            let (len, _) = socket.recv_from(&mut buffer).expect("server answers");
+            let message = decode(&buffer[..len]).expect("server sends lobby messages");
+            if wanted(&message) {
+                return message;
+            }
+        }
+    }
+
+    #[test]
+    fn ready_players_start_until_acknowledged() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let server = socket.local_addr().unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let server_thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || Server::new(socket).run(&stop))
+        };
+
+        let (host, guest) = (client(), client());
+        send(&host, server, ClientMessage::Host { win_condition: WinCondition::Stock(3), stage: "Tower".into() });
+        let ServerMessage::Lobby { lobby, .. } = receive(&host, |m| matches!(m, ServerMessage::Lobby { .. })) else {
+            unreachable!()
+        };
+        send(&guest, server, ClientMessage::Join { lobby: lobby.id });
+        receive(&guest, |m| matches!(m, ServerMessage::Lobby { .. }));
+        for socket in [&host, &guest] {
+            send(socket, server, ClientMessage::Pick { fighter: 0, palette: 0, ready: true });
+        }
+
+        let is_start = |m: &ServerMessage| matches!(m, ServerMessage::Start { .. });
+        let ServerMessage::Start { seed, player: 1, peer } = receive(&host, is_start) else {
+            panic!("host is player 1");
+        };
+        assert_eq!(peer, guest.local_addr().unwrap());
+        let ServerMessage::Start { seed: guest_seed, player: 2, peer } = receive(&guest, is_start) else {
+            panic!("guest is player 2");
+        };
+        assert_eq!(peer, host.local_addr().unwrap());
+        assert_eq!(seed, guest_seed);
+
+        // As if the guest's `Start` got lost, its next heartbeat gets it again.
+        send(&host, server, ClientMessage::Started);
+        send(&guest, server, ClientMessage::Heartbeat);
+        assert!(matches!(receive(&guest, is_start), ServerMessage::Start { player: 2, .. }));
+
+        send(&guest, server, ClientMessage::Started);
+        let ServerMessage::Lobby { lobby, .. } = receive(&guest, |m| matches!(m,
+            ServerMessage::Lobby { lobby, .. } if lobby.members.iter().all(|member| !member.ready))) else {
+            unreachable!()
+        };
+        assert_eq!(lobby.members.len(), LOBBY_SIZE);
+
+        stop.store(true, Ordering::Relaxed);
+        server_thread.join().unwrap();
+    }
+}
@@ -0,0 +1,226 @@
+//! Playable characters, see `assets/data/characters.ron`. The built-in
+//! characters are compiled in, so headless apps can use them without an
+//! `AssetServer`, and replaced by the asset file once it's loaded.
+
+use bevy::asset::io::Reader;
+use bevy::asset::{AssetLoader, LoadContext};
+use bevy::platform::collections::HashMap;
+use bevy::prelude::*;
+use serde::Deserialize;
+use std::fmt;
+
+use crate::combat::{BoxShape, DEFAULT_HURTBOX};
+use crate::stage::rgb;
+
+/// Asset path of the characters.
+const CHARACTERS_PATH: &str = "data/characters.ron";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Character {
+    pub name: String,
+    /// Mass of the character, heavier characters are launched less far.
+    pub weight: f32,
+    pub ground_speed: f32,
+    pub air_speed: f32,
+    pub jump_velocity: f32,
+    /// Number of jumps before landing again, including the one from the ground.
+    pub jumps: u32,
+    pub special: SpecialMove,
+    /// Area in which the character can be hit, relative to its position.
+    #[serde(default = "default_hurtbox")]
+    pub hurtbox: BoxShape,
+    /// Asset path of the texture.
+    pub sprite: String,
+    /// Tints of the sprite to pick from.
+    pub palettes: Vec<(f32, f32, f32)>,
+}
+
+fn default_hurtbox() -> BoxShape {
+    DEFAULT_HURTBOX
+}
+
+impl Character {
+    pub fn palette(&self, palette: usize) -> Color {
+        self.palettes.get(palette).copied().map_or(Color::WHITE, rgb)
+    }
+}
+
+/// Boost of the special move.
+#[derive(Component, Debug, Clone, Deserialize)]
+pub struct SpecialMove {
+    pub velocity: f32,
+    /// Mass while the special move lasts.
+    pub mass: f32,
+    /// Seconds the increased mass lasts.
+    pub duration: f32,
+    /// Seconds until the special move can be used again.
+    pub cooldown: f32,
+}
+
+/// Index of a player's character in the [`CharacterRegistry`].
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fighter(pub usize);
+
+/// Tint of a player's sprite from their palette.
+#[derive(Component, Clone, Copy)]
+pub struct Tint(pub Color);
+
+/// Mass of a character when no special move is active.
+#[derive(Component)]
+pub struct Weight(pub f32);
+
+#[derive(Resource, Asset, TypePath, Debug, Clone, Deserialize)]
+pub struct CharacterRegistry {
+    pub characters: Vec<Character>,
+}
+
+impl CharacterRegistry {
+    pub fn from_ron(ron: &str) -> Result<Self, ron::error::SpannedError> {
+        ron::from_str(ron)
+    }
+
+    pub fn get(&self, fighter: Fighter) -> &Character {
+        &self.characters[fighter.0 % self.characters.len()]
+    }
+
+    pub fn name(&self, fighter: Fighter) -> &str {
+        &self.get(fighter).name
+    }
+}
+
+impl Default for CharacterRegistry {
+    fn default() -> Self {
+        CharacterRegistry::from_ron(include_str!("../assets/data/characters.ron"))
+            .expect("built-in characters are valid")
+    }
+}
+
+#[derive(Debug)]
+pub enum CharacterLoaderError {
+    Io(std::io::Error),
+    Ron(ron::error::SpannedError),
+}
+
+impl fmt::Display for CharacterLoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CharacterLoaderError::Io(e) => { write!(f, "could not read characters: {}", e) }
+            CharacterLoaderError::Ron(e) => { write!(f, "invalid characters: {}", e) }
+        }
+    }
+}
+
+impl std::error::Error for CharacterLoaderError {}
+
+#[derive(Default, TypePath)]
+pub struct CharacterLoader;
+
+impl AssetLoader for CharacterLoader {
+    type Asset = CharacterRegistry;
+    type Settings = ();
+    type Error = CharacterLoaderError;
+
+    async fn load(&self,
+                  reader: &mut dyn Reader,
+                  _settings: &(),
+                  _load_context: &mut LoadContext<'_>) -> Result<CharacterRegistry, CharacterLoaderError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await.map_err(CharacterLoaderError::Io)?;
+        ron::de::from_bytes(&bytes).map_err(CharacterLoaderError::Ron)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["ron"]
+    }
+}
+
+/// Handle of the file backing the [`CharacterRegistry`].
+#[derive(Resource)]
+pub struct CharacterHandle(pub Handle<CharacterRegistry>);
+
+fn load_characters(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(CharacterHandle(asset_server.load(CHARACTERS_PATH)));
+}
+
+/// Replaces the built-in characters once their file is loaded, and
+/// whenever it changes on disk.
+fn apply_characters(mut events: MessageReader<AssetEvent<CharacterRegistry>>,
+                    handle: Res<CharacterHandle>,
+                    registries: Res<Assets<CharacterRegistry>>,
+                    mut registry: ResMut<CharacterRegistry>) {
+    for event in events.read() {
+        if let AssetEvent::LoadedWithDependencies { id } | AssetEvent::Modified { id } = event
+            && *id == handle.0.id()
+            && let Some(loaded) = registries.get(*id) {
+            if loaded.characters.is_empty() {
+                warn!("{} has no characters, keeping the current ones", CHARACTERS_PATH);
+                continue;
+            }
+            info!("{} characters loaded", loaded.characters.len());
+            *registry = loaded.clone();
+        }
+    }
+}
+
+/// Loads the characters from their asset file, needs bevy's `AssetPlugin`.
+pub struct CharacterPlugin;
+impl Plugin for CharacterPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<CharacterRegistry>();
+        app.init_asset_loader::<CharacterLoader>();
+        app.init_resource::<CharacterRegistry>();
+        app.add_systems(Startup, load_characters);
+        app.add_systems(Update, apply_characters.run_if(resource_exists::<CharacterHandle>));
+    }
+}
+
+/// Character of a player who didn't pick one: odd players get the first
+/// character, even players the second.
+pub fn default_fighter(player: u32) -> Fighter {
+    Fighter(((player + 1) % 2) as usize)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CharacterPick {
+    pub fighter: Fighter,
+    pub palette: usize,
+    pub ready: bool,
+}
+
+/// Characters and palettes picked in the character select.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct CharacterSelection {
+    pub picks: HashMap<u32, CharacterPick>,
+}
+
+impl CharacterSelection {
+    pub fn pick(&self, player: u32) -> CharacterPick {
+        self.picks.get(&player).copied().unwrap_or(CharacterPick {
+            fighter: default_fighter(player),
+            palette: 0,
+            ready: false,
+        })
+    }
+
+    pub fn pick_mut(&mut self, player: u32) -> &mut CharacterPick {
+        let pick = self.pick(player);
+        self.picks.entry(player).or_insert(pick)
+    }
+
+    /// Palettes of players `1..=player_count`. Players who picked the same
+    /// character and palette as a lower player get the next free palette,
+    /// so mirror matches can be told apart.
+    pub fn palettes(&self, registry: &CharacterRegistry, player_count: u32) -> Vec<usize> {
+        let mut taken: Vec<(Fighter, usize)> = Vec::new();
+        (1..=player_count).map(|player| {
+            let pick = self.pick(player);
+            let count = registry.get(pick.fighter).palettes.len().max(1);
+            let palette = (0..count)
+                .map(|i| (pick.palette + i) % count)
+                .find(|palette| !taken.contains(&(pick.fighter, *palette)))
+                .unwrap_or(pick.palette % count);
+            taken.push((pick.fighter, palette));
+            palette
+        }).collect()
+    }
+}
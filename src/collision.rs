@@ -0,0 +1,340 @@
+use bevy::prelude::*;
+use bevy::math::bounding::{Aabb2d, BoundingVolume};
+
+use crate::combat::{
+    body_hit_power, HitEvent, BODY_HIT_BASE_KNOCKBACK, BODY_HIT_DAMAGE, BODY_HIT_KNOCKBACK_GROWTH,
+};
+use crate::input::{Action, ActionState};
+use crate::physics::{Mass, PhysicsSubstep, Substeps, Velocity};
+use crate::platform::{MovingPlatform, Vanished};
+use crate::{GameSystems, Player};
+
+#[derive(Component)]
+pub struct Platform;
+
+/// A platform which only stops players coming down onto it from above, so
+/// they can jump up through it. Holding down drops through it.
+#[derive(Component)]
+pub struct OneWay;
+
+/// How far the feet of a player can be below the top of a one-way platform
+/// for it to still catch them, to make up for rounding and for the pushes
+/// of other players standing on them.
+const ONE_WAY_TOLERANCE: f32 = 1.;
+
+#[derive(Component, Clone, Default)]
+pub struct OnPlatform {
+    /// Whether the player stands on a platform or on another player.
+    pub grounded: bool,
+    /// The [`MovingPlatform`] the player stands on, which carries them along.
+    pub carrier: Option<Entity>,
+}
+
+/// Where a moving box touches another one, found by [`sweep`] or, with an
+/// entry of 0 and an exit of 1, by [`penetration`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Contact {
+    /// Fraction of the displacement at which the boxes start to touch.
+    pub entry: f32,
+    /// Fraction of the displacement at which they stop overlapping again.
+    pub exit: f32,
+    /// Normal of the touched side of the other box, pointing away from it.
+    pub normal: Vec2,
+}
+
+/// Entry and exit time of an interval `[min, max]` moving by `displacement`
+/// into `[other_min, other_max]`, or `None` if it never overlaps.
+fn sweep_axis(min: f32, max: f32, displacement: f32, other_min: f32, other_max: f32) -> Option<(f32, f32)> {
+    if displacement == 0. {
+        // Touching intervals don't overlap, so boxes can slide along each other.
+        return (max > other_min && min < other_max).then_some((f32::NEG_INFINITY, f32::INFINITY));
+    }
+    let (entry, exit) = if displacement > 0. {
+        (other_min - max, other_max - min)
+    }
+    else {
+        (other_max - min, other_min - max)
+    };
+    Some((entry / displacement, exit / displacement))
+}
+
+/// Swept AABB test of `moving`, displaced by `displacement`, against the
+/// resting box `other`. Finds a contact if the boxes start to touch during
+/// the displacement, including when `moving` would pass through `other`
+/// completely, but not if they already overlap at its start, see
+/// [`penetration`] for that.
+pub fn sweep(moving: Aabb2d, displacement: Vec2, other: Aabb2d) -> Option<Contact> {
+    let (entry_x, exit_x) = sweep_axis(moving.min.x, moving.max.x, displacement.x, other.min.x, other.max.x)?;
+    let (entry_y, exit_y) = sweep_axis(moving.min.y, moving.max.y, displacement.y, other.min.y, other.max.y)?;
+    let entry = entry_x.max(entry_y);
+    let exit = exit_x.min(exit_y);
+    if entry >= exit || !(0. ..=1.).contains(&entry) {
+        return None;
+    }
+    // The axis entered last is the one the boxes touch on, landings win ties.
+    let normal = if entry_x > entry_y {
+        Vec2::new(-displacement.x.signum(), 0.)
+    }
+    else {
+        Vec2::new(0., -displacement.y.signum())
+    };
+    Some(Contact { entry, exit, normal })
+}
+
+/// How far `moving` has to move along the axis aligned `normal` to touch
+/// `other` from the side `normal` points to. Negative if it's already
+/// further than that.
+pub fn separation(moving: Aabb2d, other: Aabb2d, normal: Vec2) -> f32 {
+    (moving.half_size() + other.half_size()).dot(normal.abs()) - (moving.center() - other.center()).dot(normal)
+}
+
+/// Contact of two overlapping boxes, on the side `moving` is pushed out of
+/// `other` the least, or `None` if they don't overlap or only touch.
+pub fn penetration(moving: Aabb2d, other: Aabb2d) -> Option<Contact> {
+    let normal = [Vec2::Y, Vec2::NEG_Y, Vec2::X, Vec2::NEG_X].into_iter()
+        .min_by(|a, b| separation(moving, other, *a).total_cmp(&separation(moving, other, *b)))?;
+    (separation(moving, other, normal) > 0.).then_some(Contact { entry: 0., exit: 1., normal })
+}
+
+fn bounding_box(tf: &Transform) -> Aabb2d {
+    Aabb2d::new(tf.translation.truncate(), tf.scale.truncate() / 2.)
+}
+
+type PlatformQuery<'a> = (Entity, &'a Transform, Has<OneWay>, Option<&'a MovingPlatform>);
+
+fn platform_collide(time: Res<Time>,
+           substeps: Res<Substeps>,
+           mut query1: Query<(&mut Transform, &mut Velocity, &mut OnPlatform, &ActionState), Without<Platform>>,
+           query2: Query<PlatformQuery, (With<Platform>, Without<Vanished>)>) {
+    let dt = substeps.secs(&time);
+    for (mut tf1, mut v1, mut on_platform, action_state) in &mut query1 {
+        // Players ride along with the platform they stood on.
+        let carry = on_platform.carrier
+            .and_then(|carrier| query2.get(carrier).ok())
+            .and_then(|(.., moving)| moving)
+            .map_or(Vec2::ZERO, |moving| moving.velocity);
+        tf1.translation += (carry * dt).extend(0.);
+        let moved = (v1.0.truncate() + carry) * dt;
+        let mut grounded = false;
+        let mut carrier = None;
+        for (platform, tf2, one_way, moving) in &query2 {
+            let platform_velocity = moving.map_or(Vec2::ZERO, |moving| moving.velocity);
+            let bb1 = bounding_box(&tf1);
+            // In the frame of reference of the platform, which doesn't move there.
+            let displacement = moved - platform_velocity * dt;
+            let bb1_before = Aabb2d::new(bb1.center() - displacement, bb1.half_size());
+            let bb2 = bounding_box(tf2);
+            if one_way && (action_state.pressed(Action::Down) || bb1_before.min.y < bb2.max.y - ONE_WAY_TOLERANCE) {
+                continue;
+            }
+            let Some(mut contact) = sweep(bb1_before, displacement, bb2).or_else(|| penetration(bb1, bb2)) else {
+                continue;
+            };
+            if one_way {
+                // Feet only a bit below the top step up onto it.
+                contact.normal = Vec2::Y;
+            }
+            let push = separation(bb1, bb2, contact.normal);
+            if push <= 0. {
+                continue;
+            }
+            tf1.translation += (contact.normal * push).extend(0.);
+            // Stops the movement into the platform, but not along it.
+            let into = v1.0.truncate().dot(contact.normal).min(0.);
+            v1.0 -= (contact.normal * into).extend(0.);
+            if contact.normal == Vec2::Y {
+                grounded = true;
+                carrier = moving.is_some().then_some(platform);
+            }
+        }
+        if !grounded {
+            // Players leaving a moving platform keep its momentum.
+            v1.0 += carry.extend(0.);
+        }
+        *on_platform = OnPlatform { grounded, carrier };
+    }
+}
+
+/// Launch direction of body hits: away from the attacker and a bit upwards.
+fn launch_direction(attacker_to_victim: Vec2) -> Vec2 {
+    (attacker_to_victim.normalize_or_zero() + Vec2::new(0., 0.5)).normalize_or_zero()
+}
+
+fn player_collide(time: Res<Time>,
+                  substeps: Res<Substeps>,
+                  mut commands: Commands,
+                  mut query: Query<(Entity, &mut Transform, &mut Velocity, &mut OnPlatform, &Mass, &Player)>) {
+    let dt = substeps.secs(&time);
+    // Pairs are resolved in player order rather than in storage order, so
+    // the outcome doesn't depend on how the entities were spawned.
+    let mut players: Vec<(u32, Entity)> = query.iter().map(|(e, .., player)| (player.0, e)).collect();
+    players.sort_unstable();
+    let pairs = players.iter().enumerate()
+        .flat_map(|(i, &(_, e1))| players[i + 1..].iter().map(move |&(_, e2)| [e1, e2]));
+    for pair in pairs {
+        let Ok([(e1, mut tf1, mut v1, mut jump_charge1, m1, _),
+                (e2, mut tf2, mut v2, mut jump_charge2, m2, _)]) = query.get_many_mut(pair) else {
+            continue;
+        };
+        let bb1 = bounding_box(&tf1);
+        let bb2 = bounding_box(&tf2);
+        // In the frame of reference of player 2, which doesn't move there.
+        let displacement = (v1.0 - v2.0).truncate() * dt;
+        let bb1_before = Aabb2d::new(bb1.center() - v1.0.truncate() * dt, bb1.half_size());
+        let bb2_before = Aabb2d::new(bb2.center() - v2.0.truncate() * dt, bb2.half_size());
+        if let Some(contact) = sweep(bb1_before, displacement, bb2_before).or_else(|| penetration(bb1, bb2)) {
+            let one_to_two = (tf2.translation - tf1.translation).truncate();
+            let hit = match (
+                body_hit_power(v1.0.xy(), v2.0.xy(), m1.0, one_to_two),
+                body_hit_power(v2.0.xy(), v1.0.xy(), m2.0, -one_to_two),
+            ) {
+                (Some(p1), Some(p2)) if p2 > p1 => Some((e2, e1, p2, -one_to_two)),
+                (Some(p1), _) => Some((e1, e2, p1, one_to_two)),
+                (None, Some(p2)) => Some((e2, e1, p2, -one_to_two)),
+                (None, None) => None,
+            };
+            if let Some((attacker, victim, power, direction)) = hit {
+                commands.trigger(HitEvent {
+                    attacker,
+                    victim,
+                    damage: BODY_HIT_DAMAGE * power,
+                    direction: launch_direction(direction),
+                    base_knockback: BODY_HIT_BASE_KNOCKBACK * power,
+                    knockback_growth: BODY_HIT_KNOCKBACK_GROWTH * power,
+                });
+            }
+            // Both players are pushed apart by half the overlap.
+            let push = separation(bb1, bb2, contact.normal).max(0.) / 2.;
+            tf1.translation += (contact.normal * push).extend(0.);
+            tf2.translation -= (contact.normal * push).extend(0.);
+            if contact.normal == Vec2::Y {
+                jump_charge1.grounded = true;
+            }
+            else if contact.normal == Vec2::NEG_Y {
+                jump_charge2.grounded = true;
+            }
+            let v1_new = 2. * (m1.0 * v1.0 + m2.0 * v2.0) / (m1.0 + m2.0) - v1.0;
+            let v2_new = 2. * (m1.0 * v1.0 + m2.0 * v2.0) / (m1.0 + m2.0) - v2.0;
+            v1.0 = v1_new;
+            v2.0 = v2_new;
+        }
+    }
+}
+
+pub struct CollisionPlugin;
+impl Plugin for CollisionPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(PhysicsSubstep, (
+            platform_collide,
+            player_collide,
+        ).chain().in_set(GameSystems::Collide));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(center: Vec2, half_size: f32) -> Aabb2d {
+        Aabb2d::new(center, Vec2::splat(half_size))
+    }
+
+    /// 100 wide and 50 high, with its top at y = 0.
+    fn platform() -> Aabb2d {
+        Aabb2d::new(Vec2::new(0., -25.), Vec2::new(50., 25.))
+    }
+
+    #[test]
+    fn lands_on_top() {
+        let contact = sweep(square(Vec2::new(0., 20.), 10.), Vec2::new(0., -20.), platform()).unwrap();
+        assert_eq!(contact.entry, 0.5);
+        assert_eq!(contact.normal, Vec2::Y);
+    }
+
+    #[test]
+    fn hits_the_side() {
+        let contact = sweep(square(Vec2::new(-80., -25.), 10.), Vec2::new(40., 0.), platform()).unwrap();
+        assert_eq!(contact.entry, 0.5);
+        assert_eq!(contact.exit, 3.5);
+        assert_eq!(contact.normal, Vec2::NEG_X);
+    }
+
+    #[test]
+    fn bumps_the_underside() {
+        let contact = sweep(square(Vec2::new(30., -70.), 10.), Vec2::new(0., 20.), platform()).unwrap();
+        assert_eq!(contact.normal, Vec2::NEG_Y);
+    }
+
+    #[test]
+    fn corner_is_touched_on_the_axis_entered_last() {
+        // Reaches the height of the top before getting above the platform.
+        let contact = sweep(square(Vec2::new(-70., 20.), 10.), Vec2::new(20., -40.), platform()).unwrap();
+        assert_eq!(contact.normal, Vec2::NEG_X);
+        // Gets above the platform before reaching the height of the top.
+        let contact = sweep(square(Vec2::new(-70., 30.), 10.), Vec2::new(20., -40.), platform()).unwrap();
+        assert_eq!(contact.normal, Vec2::Y);
+        // Exactly onto the corner.
+        let contact = sweep(square(Vec2::new(-70., 20.), 10.), Vec2::new(20., -20.), platform()).unwrap();
+        assert_eq!(contact.entry, 0.5);
+        assert_eq!(contact.normal, Vec2::Y);
+    }
+
+    #[test]
+    fn misses_past_the_corner() {
+        assert_eq!(sweep(square(Vec2::new(-70., 20.), 10.), Vec2::new(5., -40.), platform()), None);
+    }
+
+    #[test]
+    fn zero_displacement_never_sweeps() {
+        assert_eq!(sweep(square(Vec2::new(0., 20.), 10.), Vec2::ZERO, platform()), None);
+        // Already overlapping is left to the penetration test.
+        assert_eq!(sweep(square(Vec2::new(0., 5.), 10.), Vec2::ZERO, platform()), None);
+    }
+
+    #[test]
+    fn zero_displacement_on_one_axis() {
+        // Falling straight down next to the platform.
+        assert_eq!(sweep(square(Vec2::new(60., 20.), 10.), Vec2::new(0., -40.), platform()), None);
+        // Walking along the top while resting on it.
+        let resting = square(Vec2::new(0., 10.), 10.);
+        assert_eq!(sweep(resting, Vec2::new(10., 0.), platform()), None);
+        let contact = sweep(resting, Vec2::new(10., -1.), platform()).unwrap();
+        assert_eq!(contact.entry, 0.);
+        assert_eq!(contact.normal, Vec2::Y);
+        for contact in [contact, sweep(resting, Vec2::new(-10., -1.), platform()).unwrap()] {
+            assert!(contact.entry.is_finite() && contact.exit.is_finite());
+        }
+    }
+
+    #[test]
+    fn moving_away_does_not_collide() {
+        assert_eq!(sweep(square(Vec2::new(0., 10.), 10.), Vec2::new(0., 20.), platform()), None);
+    }
+
+    #[test]
+    fn high_speed_does_not_pass_through() {
+        // Further than the platform is high in a single step.
+        let moving = square(Vec2::new(10., 20.), 10.);
+        let contact = sweep(moving, Vec2::new(0., -1000.), platform()).unwrap();
+        assert_eq!(contact.normal, Vec2::Y);
+        assert_eq!(contact.entry, 0.01);
+        // The push back out goes all the way to the top, not to the bottom.
+        let after = square(Vec2::new(10., -980.), 10.);
+        assert_eq!(separation(after, platform(), contact.normal), 990.);
+        // Diagonally past a corner.
+        let contact = sweep(square(Vec2::new(-200., 200.), 10.), Vec2::new(400., -400.), platform()).unwrap();
+        assert_eq!(contact.normal, Vec2::Y);
+    }
+
+    #[test]
+    fn penetration_pushes_out_the_shortest_way() {
+        let contact = penetration(square(Vec2::new(0., 8.), 10.), platform()).unwrap();
+        assert_eq!(contact.normal, Vec2::Y);
+        let contact = penetration(square(Vec2::new(55., -25.), 10.), platform()).unwrap();
+        assert_eq!(contact.normal, Vec2::X);
+        // Touching isn't overlapping.
+        assert_eq!(penetration(square(Vec2::new(0., 10.), 10.), platform()), None);
+        assert_eq!(penetration(square(Vec2::new(0., 30.), 10.), platform()), None);
+    }
+
+}
@@ -0,0 +1,359 @@
+use bevy::math::bounding::{Aabb2d, IntersectsVolume};
+use bevy::prelude::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::collision::OnPlatform;
+use crate::input::{Action, ActionState};
+use crate::physics::{Mass, Velocity};
+use crate::game_match::{MatchProgress, MatchStats};
+use crate::{GameSet, GameStates, GameSystems, Player, RespawnEvent};
+
+/// Relative speed above which a collision between players counts as a hit.
+pub const BODY_HIT_MIN_SPEED: f32 = 1200.;
+/// Relative speed at which a body hit of a unit mass player has a power of 1.
+pub const BODY_HIT_REFERENCE_SPEED: f32 = 3200.;
+pub const BODY_HIT_DAMAGE: f32 = 8.;
+pub const BODY_HIT_BASE_KNOCKBACK: f32 = 400.;
+pub const BODY_HIT_KNOCKBACK_GROWTH: f32 = 1200.;
+/// Damage percent is capped so knockback can't grow without bounds.
+pub const MAX_DAMAGE: f32 = 999.;
+/// Seconds after a hit in which a fall is still credited to the attacker.
+pub const KO_CREDIT_SECS: f32 = 6.;
+
+/// Attack frames are counted at 60 frames per second, independent of the
+/// tick rate.
+pub const ATTACK_FRAME_SECS: f32 = 1. / 60.;
+pub(crate) const HITBOX_COLOR: Color = Color::srgba(1.0, 0.2, 0.2, 0.4);
+
+/// Accumulated damage in percent. The more damage, the further hits launch.
+#[derive(Component, Clone, Default)]
+pub struct Damage(pub f32);
+
+/// Last player who hit this one, credited with the KO if they fall within
+/// [`KO_CREDIT_SECS`]. Falls without a recent hit are self-destructs.
+#[derive(Component, Clone, Default)]
+pub struct LastHitBy {
+    pub attacker: Option<Entity>,
+    /// Seconds since the hit.
+    pub elapsed: f32,
+}
+
+#[derive(Event)]
+pub struct HitEvent {
+    pub attacker: Entity,
+    pub victim: Entity,
+    /// Damage in percent added to the victim.
+    pub damage: f32,
+    /// Launch direction of the victim.
+    pub direction: Vec2,
+    /// Knockback speed at 0% damage, for a victim with a mass of 1.
+    pub base_knockback: f32,
+    /// Additional knockback speed per 100% of damage.
+    pub knockback_growth: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+pub enum MoveKind {
+    /// Attack while standing still on the ground.
+    Jab,
+    /// Attack while holding a direction on the ground.
+    Tilt,
+    /// Attack while pressing a direction in the same tick on the ground.
+    Smash,
+    /// Attack in the air.
+    Aerial,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct BoxShape {
+    /// Center relative to the player, for a player facing right.
+    pub offset: Vec2,
+    pub size: Vec2,
+}
+
+/// One attack move. Frame counts are in [`ATTACK_FRAME_SECS`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct AttackData {
+    pub startup: u32,
+    pub active: u32,
+    pub recovery: u32,
+    pub damage: f32,
+    /// Launch angle in degrees, 0 is forward and 90 is up.
+    pub angle: f32,
+    pub base_knockback: f32,
+    pub knockback_growth: f32,
+    pub hitbox: BoxShape,
+}
+
+impl AttackData {
+    fn startup_secs(&self) -> f32 {
+        self.startup as f32 * ATTACK_FRAME_SECS
+    }
+
+    fn total_secs(&self) -> f32 {
+        (self.startup + self.active + self.recovery) as f32 * ATTACK_FRAME_SECS
+    }
+}
+
+/// Attack moves available to the fighters, see `assets/data/moves.ron`.
+#[derive(Resource, Debug, Clone, Deserialize)]
+pub struct MoveSet {
+    pub moves: HashMap<MoveKind, AttackData>,
+}
+
+impl MoveSet {
+    pub fn from_ron(ron: &str) -> Result<Self, ron::error::SpannedError> {
+        ron::from_str(ron)
+    }
+}
+
+impl Default for MoveSet {
+    fn default() -> Self {
+        MoveSet::from_ron(include_str!("../assets/data/moves.ron"))
+            .expect("built-in move set is valid")
+    }
+}
+
+/// Direction a player is facing, -1 for left and 1 for right.
+#[derive(Component, Clone)]
+pub struct Facing(pub f32);
+
+/// Hurtbox of characters which don't define their own.
+pub const DEFAULT_HURTBOX: BoxShape = BoxShape { offset: Vec2::new(0., -3.), size: Vec2::new(40., 44.) };
+
+/// Area in which a player can be hit, relative to its position.
+#[derive(Component)]
+pub struct Hurtbox(pub BoxShape);
+
+impl Default for Hurtbox {
+    fn default() -> Self {
+        Hurtbox(DEFAULT_HURTBOX)
+    }
+}
+
+/// Attack a player is currently performing, if any.
+#[derive(Component, Clone, Default)]
+pub struct AttackState {
+    pub attack: Option<MoveKind>,
+    pub elapsed: f32,
+    pub hitbox_spawned: bool,
+}
+
+/// Timed area of an attack which hits every hurtbox it touches once.
+#[derive(Component, Clone)]
+pub struct Hitbox {
+    pub owner: Entity,
+    pub offset: Vec2,
+    pub facing: f32,
+    pub remaining: f32,
+    pub damage: f32,
+    pub angle: f32,
+    pub base_knockback: f32,
+    pub knockback_growth: f32,
+    pub hit: Vec<Entity>,
+    /// [`MatchProgress::elapsed`] when the hitbox was spawned.
+    pub spawned_at: f32,
+}
+
+/// Launch speed of a hit, growing with the victim's damage and shrinking
+/// with its mass.
+pub fn knockback_speed(base: f32, growth: f32, percent: f32, mass: f32) -> f32 {
+    (base + growth * percent / 100.) / mass
+}
+
+/// Turns a collision between two players into a hit by the faster one, if
+/// they collided fast enough. Returns the attacker's power, which scales
+/// damage and knockback.
+pub fn body_hit_power(attacker_v: Vec2, victim_v: Vec2, attacker_mass: f32,
+                      attacker_to_victim: Vec2) -> Option<f32> {
+    let impact_speed = (attacker_v - victim_v).dot(attacker_to_victim.normalize_or_zero());
+    if impact_speed < BODY_HIT_MIN_SPEED {
+        return None;
+    }
+    Some(attacker_mass * impact_speed / BODY_HIT_REFERENCE_SPEED)
+}
+
+fn apply_hit(event: On<HitEvent>,
+             mut query: Query<(&mut Damage, &mut Velocity, &mut LastHitBy, &Mass, &Player)>,
+             mut stats: Query<&mut MatchStats>) {
+    let Ok((mut damage, mut v, mut last_hit_by, mass, player)) = query.get_mut(event.victim) else {
+        return;
+    };
+    *last_hit_by = LastHitBy { attacker: Some(event.attacker), elapsed: 0. };
+    let before = damage.0;
+    damage.0 = (damage.0 + event.damage).min(MAX_DAMAGE);
+    if let Ok(mut attacker_stats) = stats.get_mut(event.attacker) {
+        attacker_stats.damage_dealt += damage.0 - before;
+    }
+    let speed = knockback_speed(event.base_knockback, event.knockback_growth, damage.0, mass.0);
+    v.0 += (event.direction.normalize_or_zero() * speed).extend(0.);
+    info!("player {} hit, damage {:.0}%, knockback {:.0}", player.0, damage.0, speed);
+}
+
+fn reset_damage(event: On<RespawnEvent>,
+                mut query: Query<(&mut Damage, &Player)>) {
+    for (mut damage, player) in &mut query {
+        if player.0 == event.player {
+            damage.0 = 0.;
+        }
+    }
+}
+
+fn expire_last_hits(time: Res<Time>, mut query: Query<&mut LastHitBy>) {
+    for mut last_hit_by in &mut query {
+        last_hit_by.elapsed += time.delta_secs();
+        if last_hit_by.elapsed > KO_CREDIT_SECS {
+            last_hit_by.attacker = None;
+        }
+    }
+}
+
+fn update_facing(mut query: Query<(&mut Facing, &ActionState)>) {
+    for (mut facing, action_state) in &mut query {
+        match (action_state.pressed(Action::Left), action_state.pressed(Action::Right)) {
+            (true, false) => { facing.0 = -1.; }
+            (false, true) => { facing.0 = 1.; }
+            _ => {}
+        }
+    }
+}
+
+fn choose_move(action_state: &ActionState, on_platform: bool) -> MoveKind {
+    let directions = [Action::Left, Action::Right, Action::Up, Action::Down];
+    if !on_platform {
+        MoveKind::Aerial
+    }
+    else if directions.iter().any(|d| action_state.just_pressed(*d)) {
+        MoveKind::Smash
+    }
+    else if directions.iter().any(|d| action_state.pressed(*d)) {
+        MoveKind::Tilt
+    }
+    else {
+        MoveKind::Jab
+    }
+}
+
+fn update_attacks(mut commands: Commands,
+                  time: Res<Time>,
+                  progress: Res<MatchProgress>,
+                  move_set: Res<MoveSet>,
+                  mut query: Query<(Entity, &mut AttackState, &ActionState, &OnPlatform,
+                                    &Facing, &Transform)>) {
+    for (entity, mut attack_state, action_state, on_platform, facing, tf) in &mut query {
+        if attack_state.attack.is_some() {
+            attack_state.elapsed += time.delta_secs();
+        }
+        else if action_state.just_pressed(Action::Attack) {
+            *attack_state = AttackState {
+                attack: Some(choose_move(action_state, on_platform.grounded)),
+                ..default()
+            };
+        }
+        let Some(data) = attack_state.attack.and_then(|kind| move_set.moves.get(&kind)) else {
+            attack_state.attack = None;
+            continue;
+        };
+        if !attack_state.hitbox_spawned && attack_state.elapsed >= data.startup_secs() {
+            attack_state.hitbox_spawned = true;
+            let offset = data.hitbox.offset * Vec2::new(facing.0, 1.);
+            commands.spawn((
+                DespawnOnExit(GameStates::Game),
+                Hitbox {
+                    owner: entity,
+                    offset,
+                    facing: facing.0,
+                    remaining: data.active as f32 * ATTACK_FRAME_SECS,
+                    damage: data.damage,
+                    angle: data.angle,
+                    base_knockback: data.base_knockback,
+                    knockback_growth: data.knockback_growth,
+                    hit: Vec::new(),
+                    spawned_at: progress.elapsed,
+                },
+                Transform {
+                    translation: tf.translation + offset.extend(1.),
+                    scale: data.hitbox.size.extend(1.),
+                    ..default()
+                },
+                Sprite::from_color(HITBOX_COLOR, Vec2::ONE),
+            ));
+        }
+        if attack_state.elapsed >= data.total_secs() {
+            attack_state.attack = None;
+        }
+    }
+}
+
+fn move_hitboxes(mut commands: Commands,
+                 time: Res<Time>,
+                 mut hitboxes: Query<(Entity, &mut Hitbox, &mut Transform)>,
+                 owners: Query<&Transform, Without<Hitbox>>) {
+    for (entity, mut hitbox, mut tf) in &mut hitboxes {
+        hitbox.remaining -= time.delta_secs();
+        match owners.get(hitbox.owner) {
+            Ok(owner_tf) if hitbox.remaining > 0. => {
+                tf.translation = owner_tf.translation + hitbox.offset.extend(1.);
+            }
+            _ => { commands.entity(entity).despawn(); }
+        }
+    }
+}
+
+fn check_hitboxes(mut commands: Commands,
+                  mut hitboxes: Query<(Entity, &mut Hitbox, &Transform)>,
+                  hurtboxes: Query<(Entity, &Hurtbox, &Transform), Without<Hitbox>>,
+                  players: Query<&Player>) {
+    // Rollbacks respawn the hitboxes, which changes the order of the query,
+    // so hits landing in the same tick are applied by owner and spawn time.
+    let mut order: Vec<(u32, f32, Entity)> = hitboxes.iter()
+        .map(|(entity, hitbox, _)| {
+            (players.get(hitbox.owner).map_or(0, |player| player.0), hitbox.spawned_at, entity)
+        })
+        .collect();
+    order.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+    for (.., entity) in order {
+        let Ok((_, mut hitbox, tf)) = hitboxes.get_mut(entity) else {
+            continue;
+        };
+        if hitbox.remaining <= 0. {
+            continue;
+        }
+        let hit_bb = Aabb2d::new(tf.translation.truncate(), tf.scale.truncate() / 2.);
+        for (victim, hurtbox, victim_tf) in &hurtboxes {
+            if victim == hitbox.owner || hitbox.hit.contains(&victim) {
+                continue;
+            }
+            let hurt_bb = Aabb2d::new(
+                victim_tf.translation.truncate() + hurtbox.0.offset,
+                hurtbox.0.size / 2.
+            );
+            if hit_bb.intersects(&hurt_bb) {
+                hitbox.hit.push(victim);
+                let direction = Vec2::from_angle(hitbox.angle.to_radians()) * Vec2::new(hitbox.facing, 1.);
+                commands.trigger(HitEvent {
+                    attacker: hitbox.owner,
+                    victim,
+                    damage: hitbox.damage,
+                    direction,
+                    base_knockback: hitbox.base_knockback,
+                    knockback_growth: hitbox.knockback_growth,
+                });
+            }
+        }
+    }
+}
+
+pub struct CombatPlugin;
+impl Plugin for CombatPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MoveSet>();
+        app.add_observer(apply_hit);
+        app.add_observer(reset_damage);
+        app.add_systems(FixedUpdate, (
+            (update_facing, update_attacks).chain().in_set(GameSystems::Actions),
+            (expire_last_hits, move_hitboxes, check_hitboxes).chain().in_set(GameSystems::Hits),
+        ).in_set(GameSet));
+    }
+}
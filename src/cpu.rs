@@ -0,0 +1,440 @@
+//! CPU opponents. A [`Cpu`] plays with the same [`ActionState`] a human
+//! player's controls would write, reacting to its opponents with a delay
+//! like a human would. It knows where the edges of the platforms are, heads
+//! back to the stage when it's off it, and uses the special move both to
+//! recover and to attack.
+//!
+//! Each CPU draws its mistakes from its own generator seeded by the
+//! [`MatchSeed`] rather than from the [`MatchRng`](crate::MatchRng), so
+//! replays, which play back the recorded inputs without running the CPUs,
+//! don't desync.
+
+use bevy::math::bounding::{Aabb2d, BoundingVolume};
+use bevy::platform::collections::HashMap;
+use bevy::prelude::*;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::collision::{OnPlatform, Platform};
+use crate::combat::{AttackState, Facing};
+use crate::determinism::MatchSeed;
+use crate::game_match::spawn_players;
+use crate::input::{read_local_input, Action, ActionState, Cooldown, Jumps};
+use crate::netcode::NetSession;
+use crate::physics::Velocity;
+use crate::platform::Vanished;
+use crate::replay::ReplayPlayback;
+use crate::{GameSet, GameStates, GameSystems, Player};
+
+/// Horizontal distance to an opponent from which attacks reach.
+const ATTACK_REACH: f32 = 70.;
+/// Vertical distance to an opponent within which attacks reach.
+const ATTACK_HEIGHT: f32 = 40.;
+/// Horizontal distance CPUs keep to an opponent, to hit it without bumping
+/// into it.
+const SPACING: f32 = 50.;
+/// Horizontal distance to an opponent above within which CPUs jump at it.
+const JUMP_REACH: f32 = 150.;
+/// Distance up to which the special move is used to attack.
+const SPECIAL_ATTACK_RANGE: f32 = 300.;
+/// Chance per tick of a perfectly accurate CPU to attack with the special
+/// move when in range, so it isn't fired the instant it's charged.
+const SPECIAL_ATTACK_CHANCE: f32 = 0.05;
+/// How far CPUs stay away from the edges of the platform they stand on.
+const EDGE_MARGIN: f32 = 40.;
+/// Height above the top of a platform below which a recovering CPU jumps.
+const RECOVERY_HEIGHT: f32 = 100.;
+/// Horizontal distance to a destination within which CPUs stop walking.
+const WALK_DEADZONE: f32 = 5.;
+/// Largest error of the special move's direction at an accuracy of 0, in
+/// radians.
+const MAX_AIM_ERROR: f32 = 0.8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Difficulty {
+    Easy,
+    #[default]
+    Normal,
+    Hard,
+}
+
+impl Difficulty {
+    pub const ALL: [Difficulty; 3] = [Difficulty::Easy, Difficulty::Normal, Difficulty::Hard];
+
+    /// Seconds between something happening and the CPU reacting to it.
+    pub fn reaction_secs(self) -> f32 {
+        match self {
+            Difficulty::Easy => 0.5,
+            Difficulty::Normal => 0.25,
+            Difficulty::Hard => 0.08,
+        }
+    }
+
+    /// From 0 to 1, how precisely the CPU aims its special move and how
+    /// reliably it takes its chances to attack.
+    pub fn accuracy(self) -> f32 {
+        match self {
+            Difficulty::Easy => 0.2,
+            Difficulty::Normal => 0.55,
+            Difficulty::Hard => 0.9,
+        }
+    }
+}
+
+impl fmt::Display for Difficulty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Difficulty::Easy => { write!(f, "Easy") }
+            Difficulty::Normal => { write!(f, "Normal") }
+            Difficulty::Hard => { write!(f, "Hard") }
+        }
+    }
+}
+
+/// Parses `easy`, `normal` and `hard`.
+impl FromStr for Difficulty {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Difficulty::ALL.into_iter()
+            .find(|difficulty| difficulty.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("invalid difficulty {}", s))
+    }
+}
+
+/// Players of the next match who are controlled by the CPU.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct CpuPlayers(pub HashMap<u32, Difficulty>);
+
+/// What a CPU knows about its own player without having to look.
+struct Status {
+    position: Vec2,
+    /// Half the width of the player, the same for all players.
+    half_width: f32,
+    facing: f32,
+    on_platform: bool,
+    /// Whether the player still moves up, e.g. from its last jump.
+    rising: bool,
+    jumps_left: u32,
+    charged: bool,
+    attacking: bool,
+    /// Actions of the last tick, buttons are let go before pressing them again.
+    held: ActionState,
+}
+
+/// Writes the [`ActionState`] of a player controlled by the CPU.
+#[derive(Component, Clone)]
+pub struct Cpu {
+    pub difficulty: Difficulty,
+    rng: ChaCha8Rng,
+    /// Where the nearest opponent was in the last ticks, oldest first.
+    seen: VecDeque<Option<Vec2>>,
+}
+
+impl Cpu {
+    pub fn new(difficulty: Difficulty, seed: MatchSeed, player: u32) -> Self {
+        Cpu {
+            difficulty,
+            rng: ChaCha8Rng::seed_from_u64(seed.0 ^ (u64::from(player) << 32)),
+            seen: VecDeque::new(),
+        }
+    }
+
+    fn chance(&mut self, probability: f32) -> bool {
+        self.rng.random::<f32>() < probability
+    }
+
+    /// `direction` turned by an error which shrinks with the accuracy.
+    fn aim(&mut self, direction: Vec2) -> Vec2 {
+        let error = self.rng.random_range(-1.0..=1.0) * MAX_AIM_ERROR * (1. - self.difficulty.accuracy());
+        Vec2::from_angle(error).rotate(direction)
+    }
+}
+
+/// The highest platform a player `2 * half_width` wide at `position` is
+/// above.
+fn ground_below(platforms: &[Aabb2d], position: Vec2, half_width: f32) -> Option<Aabb2d> {
+    platforms.iter()
+        .filter(|p| {
+            (p.min.x - half_width..=p.max.x + half_width).contains(&position.x) && p.max.y <= position.y
+        })
+        .max_by(|a, b| a.max.y.total_cmp(&b.max.y))
+        .copied()
+}
+
+/// The point on top of `platform` nearest to `position`, away from the
+/// edges. From below the platform it's beside the platform instead, so the
+/// way up doesn't bump into the platform's underside.
+fn landing_point(platform: &Aabb2d, position: Vec2) -> Vec2 {
+    if position.y < platform.min.y && (platform.min.x..=platform.max.x).contains(&position.x) {
+        let x = if position.x < platform.center().x {
+            platform.min.x - EDGE_MARGIN
+        }
+        else {
+            platform.max.x + EDGE_MARGIN
+        };
+        return Vec2::new(x, platform.max.y);
+    }
+    let margin = EDGE_MARGIN.min(platform.half_size().x);
+    Vec2::new(position.x.clamp(platform.min.x + margin, platform.max.x - margin), platform.max.y)
+}
+
+fn walk_toward(next: &mut ActionState, dx: f32) {
+    if dx < -WALK_DEADZONE {
+        next.press(Action::Left);
+    }
+    else if dx > WALK_DEADZONE {
+        next.press(Action::Right);
+    }
+}
+
+/// Presses `action` unless it was held in the last tick, so it registers as
+/// just pressed.
+fn tap(next: &mut ActionState, status: &Status, action: Action) {
+    if !status.held.pressed(action) {
+        next.press(action);
+    }
+}
+
+/// Heads for the nearest platform, jumping when falling close to its top
+/// and using the special move once out of jumps and below it.
+fn recover(cpu: &mut Cpu, status: &Status, platforms: &[Aabb2d], next: &mut ActionState) {
+    let position = status.position;
+    let Some(landing) = platforms.iter()
+        .map(|platform| landing_point(platform, position))
+        .min_by(|a, b| a.distance_squared(position).total_cmp(&b.distance_squared(position))) else {
+        return;
+    };
+    walk_toward(next, landing.x - position.x);
+    if position.y > landing.y + RECOVERY_HEIGHT || status.rising {
+        return;
+    }
+    if status.jumps_left > 0 {
+        tap(next, status, Action::Jump);
+    }
+    else if status.charged && position.y < landing.y {
+        let direction = cpu.aim((landing - position).normalize_or_zero());
+        next.set_stick(direction);
+        next.press(Action::Special);
+    }
+}
+
+/// Walks up to the opponent at `target` and attacks once in reach. Doesn't
+/// walk off `ground` unless the opponent is on another platform and there's
+/// a jump left to get over there.
+fn fight(cpu: &mut Cpu,
+         target: Option<Vec2>,
+         status: &Status,
+         ground: Aabb2d,
+         platforms: &[Aabb2d],
+         next: &mut ActionState) {
+    let Some(target) = target else {
+        return;
+    };
+    let offset = target - status.position;
+    let target_ground = ground_below(platforms, target, status.half_width);
+    let accuracy = cpu.difficulty.accuracy();
+    let margin = EDGE_MARGIN.min(ground.half_size().x);
+    let spot = target.x - offset.x.signum() * SPACING;
+    let destination = spot.clamp(ground.min.x + margin, ground.max.x - margin);
+    let at_edge = destination != spot && (destination - status.position.x).abs() <= margin;
+    match target_ground {
+        Some(other) if other != ground && at_edge && (status.on_platform || status.jumps_left > 0) => {
+            walk_toward(next, offset.x);
+            tap(next, status, Action::Jump);
+        }
+        _ => { walk_toward(next, destination - status.position.x); }
+    }
+    if offset.x.abs() <= ATTACK_REACH && offset.y.abs() <= ATTACK_HEIGHT {
+        if !status.attacking && cpu.chance(accuracy * accuracy) {
+            if status.facing != offset.x.signum() {
+                walk_toward(next, offset.x);
+            }
+            tap(next, status, Action::Attack);
+        }
+    }
+    else if offset.y > ATTACK_HEIGHT && offset.x.abs() <= JUMP_REACH {
+        if status.on_platform && cpu.chance(accuracy) {
+            tap(next, status, Action::Jump);
+        }
+    }
+    else if status.charged && target_ground.is_some() && offset.length() <= SPECIAL_ATTACK_RANGE
+        && cpu.chance(accuracy * SPECIAL_ATTACK_CHANCE) {
+        let direction = cpu.aim(offset.normalize_or_zero());
+        next.set_stick(direction);
+        next.press(Action::Special);
+    }
+}
+
+type CpuQuery<'a> = (&'a mut Cpu,
+                     &'a mut ActionState,
+                     &'a Transform,
+                     &'a Velocity,
+                     &'a OnPlatform,
+                     &'a Jumps,
+                     &'a Cooldown,
+                     &'a AttackState,
+                     &'a Facing,
+                     &'a Player);
+
+fn cpu_input(time: Res<Time<Fixed>>,
+             platforms: Query<&Transform, (With<Platform>, Without<Vanished>)>,
+             players: Query<(&Transform, &Player)>,
+             mut cpus: Query<CpuQuery>) {
+    let platforms: Vec<Aabb2d> = platforms.iter()
+        .map(|tf| Aabb2d::new(tf.translation.truncate(), tf.scale.truncate() / 2.))
+        .collect();
+    let mut positions: Vec<(u32, Vec2)> = players.iter()
+        .map(|(tf, player)| (player.0, tf.translation.truncate()))
+        .collect();
+    positions.sort_by_key(|(player, _)| *player);
+    for (mut cpu, mut action_state, tf, v, on_platform, jumps, cooldown, attack_state, facing, player) in &mut cpus {
+        let position = tf.translation.truncate();
+        let target = positions.iter()
+            .filter(|(other, _)| *other != player.0)
+            .map(|(_, target)| *target)
+            .min_by(|a, b| a.distance_squared(position).total_cmp(&b.distance_squared(position)));
+        let reaction = (cpu.difficulty.reaction_secs() / time.timestep().as_secs_f32()).round() as usize;
+        cpu.seen.push_back(target);
+        while cpu.seen.len() > reaction + 1 {
+            cpu.seen.pop_front();
+        }
+        let target = cpu.seen[0];
+        let status = Status {
+            position,
+            half_width: tf.scale.x / 2.,
+            facing: facing.0,
+            on_platform: on_platform.grounded,
+            rising: v.0.y > 0.,
+            jumps_left: jumps.left,
+            charged: cooldown.charge,
+            attacking: attack_state.attack.is_some(),
+            held: *action_state,
+        };
+        let mut next = ActionState::default();
+        match ground_below(&platforms, position, status.half_width) {
+            Some(ground) => { fight(&mut cpu, target, &status, ground, &platforms, &mut next); }
+            None => { recover(&mut cpu, &status, &platforms, &mut next); }
+        }
+        action_state.advance();
+        action_state.set_bits(next.bits());
+        action_state.set_stick(next.stick());
+    }
+}
+
+fn attach_cpus(mut commands: Commands,
+               cpu_players: Res<CpuPlayers>,
+               seed: Res<MatchSeed>,
+               query: Query<(Entity, &Player)>) {
+    for (entity, player) in &query {
+        if let Some(difficulty) = cpu_players.0.get(&player.0) {
+            commands.entity(entity).insert(Cpu::new(*difficulty, *seed, player.0));
+        }
+    }
+}
+
+/// Lets the CPU play the players in [`CpuPlayers`].
+pub struct CpuPlugin;
+impl Plugin for CpuPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<CpuPlayers>();
+        app.add_systems(OnEnter(GameStates::Game), attach_cpus.after(spawn_players));
+        app.add_systems(FixedUpdate, cpu_input
+                        .after(read_local_input)
+                        .run_if(not(resource_exists::<ReplayPlayback>))
+                        .run_if(not(resource_exists::<NetSession>))
+                        .in_set(GameSystems::Input)
+                        .in_set(GameSet));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_match::Score;
+    use crate::sim::{headless_app, SimulationConfig};
+
+    /// Where players standing on the main platform of the default stage are.
+    const GROUND_Y: f32 = -100.;
+
+    /// A match of the CPU as player 1 against an idle player 2, with the
+    /// players already spawned.
+    fn cpu_app(difficulty: Difficulty) -> App {
+        let mut app = headless_app(&SimulationConfig {
+            cpus: CpuPlayers([(1, difficulty)].into_iter().collect()),
+            ..default()
+        });
+        app.update();
+        app
+    }
+
+    fn place(app: &mut App, player: u32, position: Vec2) {
+        let world = app.world_mut();
+        for (mut tf, mut v, _) in world.query::<(&mut Transform, &mut Velocity, &Player)>()
+            .iter_mut(world)
+            .filter(|(.., p)| p.0 == player) {
+            tf.translation = position.extend(0.);
+            v.0 = Vec3::ZERO;
+        }
+    }
+
+    fn cpu_entity(app: &mut App) -> Entity {
+        let world = app.world_mut();
+        world.query_filtered::<Entity, With<Cpu>>().single(world).unwrap()
+    }
+
+    #[test]
+    fn recovers_to_a_platform() {
+        let mut app = cpu_app(Difficulty::Hard);
+        let cpu = cpu_entity(&mut app);
+        // Beside and below the platform on the right of the stage.
+        place(&mut app, 1, Vec2::new(1000., -300.));
+        let mut landed = false;
+        for _ in 0..3 * 64 {
+            app.update();
+            landed |= app.world().get::<OnPlatform>(cpu).unwrap().grounded;
+        }
+        let cpu = app.world().entity(cpu);
+        assert!(landed, "never landed, ended at {}", cpu.get::<Transform>().unwrap().translation);
+        assert_eq!(cpu.get::<Score>().unwrap().0, 0);
+    }
+
+    /// Ticks until the CPU walks toward an opponent which switched sides.
+    fn reaction_ticks(difficulty: Difficulty) -> u32 {
+        let mut app = cpu_app(difficulty);
+        let cpu = cpu_entity(&mut app);
+        // Both stay in place, out of reach of attacks.
+        let mut opponent = Vec2::new(250., GROUND_Y);
+        for tick in 0..128 {
+            if tick == 64 {
+                opponent.x = -250.;
+            }
+            place(&mut app, 1, Vec2::new(0., GROUND_Y));
+            place(&mut app, 2, opponent);
+            app.update();
+            let action_state = app.world().get::<ActionState>(cpu).unwrap();
+            if tick < 64 {
+                assert!(action_state.pressed(Action::Right));
+            }
+            else if action_state.pressed(Action::Left) {
+                return tick - 63;
+            }
+        }
+        panic!("{} CPU never turned around", difficulty);
+    }
+
+    #[test]
+    fn difficulty_sets_reaction_delay() {
+        let ticks = Difficulty::ALL.map(reaction_ticks);
+        assert!(ticks[0] > ticks[1] && ticks[1] > ticks[2], "{:?}", ticks);
+        for (difficulty, ticks) in Difficulty::ALL.into_iter().zip(ticks) {
+            let expected = difficulty.reaction_secs() * 64.;
+            assert!((ticks as f32 - expected).abs() <= 1.5, "{} CPU took {} ticks", difficulty, ticks);
+        }
+    }
+}
@@ -0,0 +1,248 @@
+//! Matches are deterministic: given the same seed and inputs, the
+//! `FixedUpdate` chain produces bit-for-bit the same state on every run.
+//! Randomness comes from the seeded [`MatchRng`], and after every tick a
+//! [`StateChecksum`] of the gameplay components is taken, so two runs, e.g.
+//! a replay and its recording, can be compared frame by frame.
+
+use bevy::prelude::*;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+
+use crate::collision::OnPlatform;
+use crate::combat::{AttackState, Facing, Hitbox, LastHitBy};
+use crate::game_match::MatchProgress;
+use crate::input::{Cooldown, Jumps};
+use crate::physics::{Acceleration, Mass, Velocity};
+use crate::{ActionState, Damage, GameSet, GameStates, GameSystems, Kos, Player, Score};
+
+/// Added to the owner's player number to sort hitboxes after all players.
+const HITBOX_KEY: u32 = 1 << 16;
+
+/// Seed of the [`MatchRng`].
+#[derive(Resource, Debug, Clone, Copy, Default)]
+pub struct MatchSeed(pub u64);
+
+/// Source of all randomness in gameplay, reseeded from [`MatchSeed`] when
+/// a match starts.
+#[derive(Resource, Clone)]
+pub struct MatchRng(pub ChaCha8Rng);
+
+impl MatchRng {
+    pub fn from_seed(seed: MatchSeed) -> Self {
+        MatchRng(ChaCha8Rng::seed_from_u64(seed.0))
+    }
+}
+
+impl Default for MatchRng {
+    fn default() -> Self {
+        MatchRng::from_seed(MatchSeed::default())
+    }
+}
+
+/// Hash of the gameplay state after the last tick of the match.
+#[derive(Resource, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StateChecksum {
+    /// Ticks played in the current match.
+    pub frame: u32,
+    pub hash: u64,
+}
+
+/// FNV-1a over little-endian bytes. Unlike std's `DefaultHasher` its output
+/// is specified, so checksums can be compared between builds and machines.
+struct StateHasher(u64);
+
+impl StateHasher {
+    fn new() -> Self {
+        StateHasher(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.bytes(&[value as u8]);
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.u32(value.to_bits());
+    }
+
+    fn vec2(&mut self, value: Vec2) {
+        self.f32(value.x);
+        self.f32(value.y);
+    }
+
+    fn vec3(&mut self, value: Vec3) {
+        self.vec2(value.truncate());
+        self.f32(value.z);
+    }
+}
+
+type ChecksumQuery<'a> = (&'a Player,
+                          &'a Transform,
+                          &'a Velocity,
+                          &'a Acceleration,
+                          &'a Mass,
+                          &'a Damage,
+                          &'a Score,
+                          &'a Kos,
+                          &'a OnPlatform,
+                          &'a Cooldown,
+                          &'a Jumps,
+                          &'a ActionState,
+                          &'a Facing,
+                          &'a AttackState,
+                          &'a LastHitBy);
+
+/// Player number of `entity`, entities themselves differ between runs.
+fn player_number(players: &Query<&Player>, entity: Entity) -> u32 {
+    players.get(entity).map_or(0, |player| player.0)
+}
+
+fn reseed_match(mut checksum: ResMut<StateChecksum>,
+                mut rng: ResMut<MatchRng>,
+                seed: Res<MatchSeed>) {
+    *checksum = StateChecksum::default();
+    *rng = MatchRng::from_seed(*seed);
+}
+
+pub fn compute_checksum(query: Query<ChecksumQuery>,
+                    hitboxes: Query<(&Hitbox, &Transform)>,
+                    players: Query<&Player>,
+                    progress: Res<MatchProgress>,
+                    rng: Res<MatchRng>,
+                    mut checksum: ResMut<StateChecksum>) {
+    // Entities are hashed one at a time and then in a fixed order, the
+    // order of queries depends on how the entities were spawned.
+    let mut entity_hashes: Vec<(u32, u64)> = query.iter()
+        .map(|(player, tf, v, accel, mass, damage, score, kos, on_platform, cooldown, jumps,
+               action_state, facing, attack_state, last_hit_by)| {
+            let mut hasher = StateHasher::new();
+            hasher.u32(player.0);
+            hasher.vec3(tf.translation);
+            hasher.vec3(v.0);
+            hasher.vec3(accel.0);
+            hasher.f32(mass.0);
+            hasher.f32(damage.0);
+            hasher.u32(score.0);
+            hasher.u32(kos.0);
+            hasher.bool(on_platform.grounded);
+            hasher.bool(on_platform.carrier.is_some());
+            hasher.u32(cooldown.ticks);
+            hasher.bool(cooldown.charge);
+            hasher.u32(jumps.left);
+            hasher.bytes(&[action_state.bits()]);
+            hasher.vec2(action_state.stick());
+            hasher.f32(facing.0);
+            hasher.u32(attack_state.attack.map_or(0, |kind| kind as u32 + 1));
+            hasher.f32(attack_state.elapsed);
+            hasher.bool(attack_state.hitbox_spawned);
+            hasher.u32(last_hit_by.attacker.map_or(0, |e| player_number(&players, e)));
+            hasher.f32(last_hit_by.elapsed);
+            (player.0, hasher.0)
+        })
+        .collect();
+    entity_hashes.extend(hitboxes.iter().map(|(hitbox, tf)| {
+        let mut hasher = StateHasher::new();
+        hasher.vec3(tf.translation);
+        hasher.vec2(hitbox.offset);
+        hasher.f32(hitbox.facing);
+        hasher.f32(hitbox.remaining);
+        hasher.f32(hitbox.damage);
+        hasher.f32(hitbox.angle);
+        hasher.f32(hitbox.base_knockback);
+        hasher.f32(hitbox.knockback_growth);
+        hasher.f32(hitbox.spawned_at);
+        let mut hit: Vec<u32> = hitbox.hit.iter().map(|e| player_number(&players, *e)).collect();
+        hit.sort_unstable();
+        for player in hit {
+            hasher.u32(player);
+        }
+        (HITBOX_KEY + player_number(&players, hitbox.owner), hasher.0)
+    }));
+    entity_hashes.sort_unstable();
+
+    checksum.frame += 1;
+    let mut hasher = StateHasher::new();
+    hasher.u32(checksum.frame);
+    hasher.f32(progress.elapsed);
+    hasher.u64(rng.0.get_word_pos() as u64);
+    for (key, hash) in entity_hashes {
+        hasher.u32(key);
+        hasher.u64(hash);
+    }
+    checksum.hash = hasher.0;
+}
+
+/// Seeds the [`MatchRng`] and keeps the [`StateChecksum`] up to date.
+pub struct DeterminismPlugin;
+impl Plugin for DeterminismPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MatchSeed>();
+        app.init_resource::<MatchRng>();
+        app.init_resource::<StateChecksum>();
+        app.add_systems(OnEnter(GameStates::Game), reseed_match);
+        app.add_systems(FixedUpdate, compute_checksum.in_set(GameSystems::Checksum).in_set(GameSet));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Difficulty;
+    use crate::input::Action;
+    use crate::sim::{run_simulation, InputScript, ScriptEvent, SimulationConfig};
+
+    /// Player 1 walks towards player 2 and attacks at `attack_frame`, player 2
+    /// is a CPU, whose mistakes depend on the seed.
+    fn config(seed: u64, attack_frame: u32) -> SimulationConfig {
+        let event = |frame, press, release| ScriptEvent { frame, player: 1, press, release };
+        let events = vec![
+            event(0, vec![Action::Left], vec![]),
+            event(20, vec![], vec![Action::Left]),
+            event(attack_frame, vec![Action::Attack], vec![]),
+        ];
+        let mut config = SimulationConfig {
+            frames: 600,
+            seed,
+            script: InputScript { events },
+            checksums: true,
+            ..default()
+        };
+        config.cpus.0.insert(2, Difficulty::Easy);
+        config
+    }
+
+    #[test]
+    fn same_seed_and_inputs_give_same_checksums() {
+        let first = run_simulation(&config(7, 40));
+        let second = run_simulation(&config(7, 40));
+        assert!(!first.checksums.is_empty());
+        assert_eq!(first.checksums, second.checksums);
+    }
+
+    #[test]
+    fn different_seed_gives_different_checksums() {
+        assert_ne!(run_simulation(&config(7, 40)).checksums, run_simulation(&config(8, 40)).checksums);
+    }
+
+    #[test]
+    fn different_inputs_give_different_checksums() {
+        let first = run_simulation(&config(7, 40)).checksums;
+        let second = run_simulation(&config(7, 41)).checksums;
+        // The state only differs from the tick of the first attack on.
+        assert_eq!(first[..40], second[..40]);
+        assert_ne!(first, second);
+    }
+}
@@ -0,0 +1,309 @@
+//! Gym-style environment for training bots against the headless
+//! simulation. [`Environment::reset`] starts a match and
+//! [`Environment::step`] holds the given actions for exactly one
+//! `FixedUpdate` tick.
+//!
+//! `platform-fighter env` speaks the same API as JSON lines over stdin and
+//! stdout, see [`run_json_lines`].
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+
+use crate::collision::OnPlatform;
+use crate::cpu::Cpu;
+use crate::game_match::{KoEvent, Kos};
+use crate::input::{Action, ActionState, Cooldown};
+use crate::physics::Velocity;
+use crate::sim::{apply_script, game_over_pending, headless_app, SimFrame, SimulationConfig};
+use crate::{Damage, GameSet, GameSystems, HitEvent, Player, Score};
+
+/// Reward for knocking another player off the stage, and penalty for
+/// falling off it.
+pub const KO_REWARD: f32 = 1.;
+/// Reward per percent of damage dealt, and penalty per percent taken.
+pub const DAMAGE_REWARD: f32 = 0.01;
+
+/// Actions a player holds for one tick.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PlayerActions {
+    #[serde(default)]
+    pub press: Vec<Action>,
+    /// Analog stick position, e.g. to aim the special move.
+    #[serde(default)]
+    pub stick: [f32; 2],
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlayerObservation {
+    pub player: u32,
+    pub position: [f32; 2],
+    pub velocity: [f32; 2],
+    pub on_platform: bool,
+    /// Whether the special move is charged.
+    pub charged: bool,
+    pub score: u32,
+    pub kos: u32,
+    pub damage: f32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Observation {
+    /// Ticks simulated so far.
+    pub frame: u32,
+    /// Players still in the match, by player number.
+    pub players: Vec<PlayerObservation>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Step {
+    pub observation: Observation,
+    /// Reward of every player for this tick, by player number.
+    pub rewards: BTreeMap<u32, f32>,
+    /// Whether the match is over or ran for the configured number of frames.
+    pub done: bool,
+}
+
+/// Actions of the trained players for the next tick.
+#[derive(Resource, Default)]
+struct StepActions(BTreeMap<u32, PlayerActions>);
+
+/// Rewards collected during the current tick.
+#[derive(Resource, Default)]
+struct StepRewards(BTreeMap<u32, f32>);
+
+/// Runs after the script, which releases everything of players without one.
+fn apply_step_actions(actions: Res<StepActions>,
+                      mut query: Query<(&mut ActionState, &Player), Without<Cpu>>) {
+    for (mut action_state, player) in &mut query {
+        let Some(actions) = actions.0.get(&player.0) else {
+            continue;
+        };
+        let mut next = ActionState::default();
+        for action in &actions.press {
+            next.press(*action);
+        }
+        action_state.set_bits(next.bits());
+        action_state.set_stick(Vec2::from_array(actions.stick));
+    }
+}
+
+fn reward_ko(event: On<KoEvent>, mut rewards: ResMut<StepRewards>) {
+    *rewards.0.entry(event.player).or_default() -= KO_REWARD;
+    if let Some(ko_by) = event.ko_by {
+        *rewards.0.entry(ko_by).or_default() += KO_REWARD;
+    }
+}
+
+fn reward_hit(event: On<HitEvent>, players: Query<&Player>, mut rewards: ResMut<StepRewards>) {
+    if let Ok(victim) = players.get(event.victim) {
+        *rewards.0.entry(victim.0).or_default() -= event.damage * DAMAGE_REWARD;
+    }
+    if let Ok(attacker) = players.get(event.attacker) {
+        *rewards.0.entry(attacker.0).or_default() += event.damage * DAMAGE_REWARD;
+    }
+}
+
+/// A match in a headless app, advanced one tick per [`Environment::step`].
+/// Players in `config.cpus` are played by the CPU, all others by the
+/// actions passed to `step`. `config.frames` limits the length of an
+/// episode and `config.script` is ignored.
+pub struct Environment {
+    config: SimulationConfig,
+    app: App,
+}
+
+impl Environment {
+    pub fn new(mut config: SimulationConfig) -> Self {
+        config.script = Default::default();
+        let app = Self::build_app(&config);
+        Environment { config, app }
+    }
+
+    fn build_app(config: &SimulationConfig) -> App {
+        let mut app = headless_app(config);
+        app.init_resource::<StepActions>();
+        app.init_resource::<StepRewards>();
+        app.add_observer(reward_ko);
+        app.add_observer(reward_hit);
+        app.add_systems(FixedUpdate, apply_step_actions
+                        .after(apply_script)
+                        .in_set(GameSystems::Input)
+                        .in_set(GameSet));
+        // Starts the match and the clock without simulating a tick yet, so
+        // each step is one tick.
+        app.update();
+        app
+    }
+
+    /// Starts a new match, with `seed` if given and the last seed otherwise.
+    pub fn reset(&mut self, seed: Option<u64>) -> Observation {
+        if let Some(seed) = seed {
+            self.config.seed = seed;
+        }
+        self.app = Self::build_app(&self.config);
+        self.observe()
+    }
+
+    /// Holds `actions` for one tick, by player number. Players without
+    /// actions hold nothing.
+    pub fn step(&mut self, actions: BTreeMap<u32, PlayerActions>) -> Step {
+        let world = self.app.world_mut();
+        world.resource_mut::<StepActions>().0 = actions;
+        world.resource_mut::<StepRewards>().0 = (1..=self.config.players).map(|p| (p, 0.)).collect();
+        self.app.update();
+        let observation = self.observe();
+        let done = game_over_pending(&self.app) || observation.frame >= self.config.frames;
+        let rewards = std::mem::take(&mut self.app.world_mut().resource_mut::<StepRewards>().0);
+        Step { observation, rewards, done }
+    }
+
+    pub fn observe(&mut self) -> Observation {
+        let world = self.app.world_mut();
+        let mut players: Vec<PlayerObservation> = world
+            .query::<(&Player, &Transform, &Velocity, &OnPlatform, &Cooldown, &Score, &Kos, &Damage)>()
+            .iter(world)
+            .map(|(player, tf, v, on_platform, cooldown, score, kos, damage)| PlayerObservation {
+                player: player.0,
+                position: tf.translation.truncate().to_array(),
+                velocity: v.0.truncate().to_array(),
+                on_platform: on_platform.grounded,
+                charged: cooldown.charge,
+                score: score.0,
+                kos: kos.0,
+                damage: damage.0,
+            })
+            .collect();
+        players.sort_by_key(|p| p.player);
+        Observation { frame: world.resource::<SimFrame>().0, players }
+    }
+}
+
+/// A line of the JSON-lines protocol, e.g. `{"reset": {"seed": 7}}` or
+/// `{"step": {"actions": {"1": {"press": ["left", "attack"]}}}}`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Request {
+    Reset {
+        #[serde(default)]
+        seed: Option<u64>,
+    },
+    Step {
+        #[serde(default)]
+        actions: BTreeMap<u32, PlayerActions>,
+    },
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// Answers each request read from `input` with a line of JSON on `output`:
+/// the [`Observation`] after a reset, the [`Step`] after a step, or
+/// `{"error": ...}` for lines which aren't requests.
+pub fn run_json_lines(config: SimulationConfig,
+                      input: impl BufRead,
+                      mut output: impl Write) -> std::io::Result<()> {
+    let mut environment = Environment::new(config);
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(Request::Reset { seed }) => serde_json::to_string(&environment.reset(seed)),
+            Ok(Request::Step { actions }) => serde_json::to_string(&environment.step(actions)),
+            Err(e) => serde_json::to_string(&ErrorResponse { error: e.to_string() }),
+        };
+        writeln!(output, "{}", response.expect("responses serialize"))?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::combat::LastHitBy;
+    use crate::cpu::Difficulty;
+
+    /// Player 1 is trained against a CPU, whose mistakes depend on the seed.
+    fn environment() -> Environment {
+        let mut config = SimulationConfig::default();
+        config.cpus.0.insert(2, Difficulty::Easy);
+        Environment::new(config)
+    }
+
+    fn walk_left() -> BTreeMap<u32, PlayerActions> {
+        BTreeMap::from([(1, PlayerActions { press: vec![Action::Left], ..default() })])
+    }
+
+    /// Observations as JSON of `steps` steps after a reset with `seed`.
+    fn episode(environment: &mut Environment, seed: u64, steps: u32) -> Vec<String> {
+        let first = environment.reset(Some(seed));
+        std::iter::once(first)
+            .chain((0..steps).map(|_| environment.step(walk_left()).observation))
+            .map(|observation| serde_json::to_string(&observation).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn step_advances_one_tick() {
+        let mut environment = environment();
+        assert_eq!(environment.reset(None).frame, 0);
+        for frame in 1..=10 {
+            let step = environment.step(BTreeMap::new());
+            assert_eq!(step.observation.frame, frame);
+            assert!(!step.done);
+        }
+    }
+
+    #[test]
+    fn ko_rewards_are_zero_sum() {
+        let mut environment = environment();
+        environment.reset(None);
+        // Player 2 hit player 1, who is then past the bottom of the blast zone.
+        let world = environment.app.world_mut();
+        let mut players = world.query::<(Entity, &Player)>();
+        let attacker = players.iter(world).find(|(_, player)| player.0 == 2).unwrap().0;
+        let (mut tf, mut last_hit_by, _) = world.query::<(&mut Transform, &mut LastHitBy, &Player)>()
+            .iter_mut(world)
+            .find(|(.., player)| player.0 == 1)
+            .unwrap();
+        tf.translation.y = -5000.;
+        *last_hit_by = LastHitBy { attacker: Some(attacker), elapsed: 0. };
+
+        let step = environment.step(BTreeMap::new());
+        assert_eq!(step.rewards[&1], -KO_REWARD);
+        assert_eq!(step.rewards[&2], KO_REWARD);
+        assert_eq!(step.rewards.values().sum::<f32>(), 0.);
+    }
+
+    #[test]
+    fn reset_with_seed_reproduces_observations() {
+        let mut environment = environment();
+        let first = episode(&mut environment, 3, 600);
+        let other_seed = episode(&mut environment, 4, 600);
+        let again = episode(&mut environment, 3, 600);
+        assert_eq!(first, again);
+        assert_ne!(first, other_seed);
+    }
+
+    #[test]
+    fn bad_lines_get_errors() {
+        let input = "not json\n{\"step\": {}}\n\n{\"reset\": {\"seed\": 1}}\n{\"jump\": {}}\n";
+        let mut output = Vec::new();
+        run_json_lines(SimulationConfig::default(), input.as_bytes(), &mut output).unwrap();
+        let responses: Vec<serde_json::Value> = String::from_utf8(output).unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(responses.len(), 4, "{:?}", responses);
+        assert!(responses[0]["error"].is_string());
+        assert_eq!(responses[1]["observation"]["frame"], 1);
+        assert_eq!(responses[2]["frame"], 0);
+        assert!(responses[3]["error"].is_string());
+    }
+}
@@ -0,0 +1,818 @@
+use bevy::ecs::entity_disabling::Disabled;
+use bevy::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::character::{
+    Character, CharacterRegistry, CharacterSelection, Fighter, SpecialMove, Tint, Weight,
+};
+use crate::collision::{OnPlatform, OneWay, Platform};
+use crate::cpu::{CpuPlayers, Difficulty};
+use crate::combat::{AttackState, Damage, Facing, Hurtbox, LastHitBy};
+use crate::determinism::MatchRng;
+use crate::netcode::NetSession;
+use crate::input::{
+    read_slot_input, Action, ActionState, Cooldown, Jumps, SlotActions, PLAYER_JUMP_VEL, SPECIAL_MOVE_DURATION, SPECIAL_MOVE_MASS,
+    SPECIAL_MOVE_VEL,
+};
+use crate::stage::{rgb, ActiveStage, BlastSide, StageChoice, StageList};
+use crate::platform::MovingPlatform;
+use crate::physics::{
+    Acceleration, FrictionForce, GravitationForce, Mass, MovementForce, Velocity,
+    GRAVTITON_FORCE, PLAYER_MOVEMENT_FORCE, PLAYER_MOVEMENT_FORCE_AIR,
+};
+use crate::{
+    GameOverSet, GameSet, GameStates, GameSystems, MatchPhase, MenuSet, PauseState, NULL_VECTOR,
+};
+
+pub const MAX_PLAYERS: u32 = 8;
+/// Damage all players start sudden death with, so the next hit decides.
+pub const SUDDEN_DEATH_DAMAGE: f32 = 300.;
+
+#[derive(Component)]
+pub struct Player(pub u32);
+
+/// Number of players in the next match, between 2 and [`MAX_PLAYERS`].
+#[derive(Resource)]
+pub struct PlayerCount(pub u32);
+
+impl Default for PlayerCount {
+    fn default() -> Self {
+        Self(2)
+    }
+}
+
+/// How a match is won.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum WinCondition {
+    /// Players are out after falling this many times, the last one left wins.
+    Stock(u32),
+    /// The player with the most KOs after this many seconds wins.
+    Timed(f32),
+    /// The first player to reach this many KOs wins.
+    FirstTo(u32),
+}
+
+impl WinCondition {
+    /// Win conditions which can be picked in the menu.
+    pub const PRESETS: [WinCondition; 3] = [
+        WinCondition::Stock(6),
+        WinCondition::Timed(180.),
+        WinCondition::FirstTo(6),
+    ];
+
+    /// The preset `step` places after the kind of this win condition.
+    pub fn cycle(self, step: i32) -> WinCondition {
+        let presets = WinCondition::PRESETS;
+        let index = presets.iter()
+            .position(|p| std::mem::discriminant(p) == std::mem::discriminant(&self))
+            .unwrap_or(0);
+        presets[(index as i32 + step).rem_euclid(presets.len() as i32) as usize]
+    }
+}
+
+impl fmt::Display for WinCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WinCondition::Stock(stocks) => { write!(f, "Stock {}", stocks) }
+            WinCondition::Timed(secs) => {
+                let secs = secs.round() as u32;
+                write!(f, "Timed {}:{:02}", secs / 60, secs % 60)
+            }
+            WinCondition::FirstTo(kos) => { write!(f, "First to {}", kos) }
+        }
+    }
+}
+
+/// Parses `stock:N`, `timed:SECS` and `first-to:N`.
+impl FromStr for WinCondition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid win condition {}", s);
+        let (kind, value) = s.split_once(':').ok_or_else(invalid)?;
+        match kind {
+            "stock" => value.parse().ok().filter(|n| *n > 0).map(WinCondition::Stock),
+            "timed" => value.parse().ok().filter(|t| *t > 0.).map(WinCondition::Timed),
+            "first-to" => value.parse().ok().filter(|n| *n > 0).map(WinCondition::FirstTo),
+            _ => None,
+        }.ok_or_else(invalid)
+    }
+}
+
+/// Rules of the next match.
+#[derive(Resource, Debug, Clone)]
+pub struct MatchRules {
+    pub win_condition: WinCondition,
+}
+
+impl Default for MatchRules {
+    fn default() -> Self {
+        Self { win_condition: WinCondition::Stock(6) }
+    }
+}
+
+#[derive(Component, Clone)]
+pub struct PlayerResult {
+    pub player: u32,
+    pub fighter: Fighter,
+    pub score: u32,
+    pub kos: u32,
+    /// 1 for the winner.
+    pub place: u32,
+    pub stats: MatchStats,
+}
+
+/// What a player did during a match, shown on the game over screen.
+#[derive(Component, Clone, Default, Serialize)]
+pub struct MatchStats {
+    pub kos: u32,
+    pub falls: u32,
+    /// Falls which weren't credited to anyone.
+    pub self_destructs: u32,
+    /// Damage in percent dealt to other players.
+    pub damage_dealt: f32,
+    pub special_moves: u32,
+    pub airborne_secs: f32,
+}
+
+/// Progress of the running match, reset when a match starts.
+#[derive(Resource, Clone, Default)]
+pub struct MatchProgress {
+    /// Seconds played, pauses excluded.
+    pub elapsed: f32,
+    /// Results of the players who are out, in order of elimination.
+    pub eliminated: Vec<PlayerResult>,
+    /// Final results, best first, once the match is decided. The match ends
+    /// right away, or in online matches once no rollback can undo it.
+    pub results: Option<Vec<PlayerResult>>,
+}
+
+/// Number of times a player fell off the stage.
+#[derive(Component, Clone)]
+pub struct Score(pub u32);
+
+/// Number of other players this player knocked off the stage.
+#[derive(Component, Clone, Default)]
+pub struct Kos(pub u32);
+
+/// A player left the blast zone.
+#[derive(Event)]
+pub struct KoEvent {
+    pub player: u32,
+    /// Player credited with the KO, `None` for a self-destruct.
+    pub ko_by: Option<u32>,
+    pub side: BlastSide,
+    /// Where the player left the blast zone.
+    pub position: Vec2,
+}
+
+#[derive(Event)]
+pub struct RespawnEvent {
+    pub player: u32,
+    pub score: u32,
+    /// Player credited with the KO, if anyone hit them before they fell.
+    pub ko_by: Option<u32>,
+}
+
+#[derive(Bundle)]
+pub struct PlayerBundle {
+    pub player: Player,
+    pub acceleration: Acceleration,
+    pub velocity: Velocity,
+    pub mass: Mass,
+    pub force_movement: MovementForce,
+    pub force_friction: FrictionForce,
+    pub force_gravitation: GravitationForce,
+    pub on_platform: OnPlatform,
+    pub special_move_cooldown: Cooldown,
+    pub special_move: SpecialMove,
+    pub jumps: Jumps,
+    pub fighter: Fighter,
+    pub weight: Weight,
+    pub tint: Tint,
+    pub action_state: ActionState,
+    pub damage: Damage,
+    pub facing: Facing,
+    pub hurtbox: Hurtbox,
+    pub attack_state: AttackState,
+    pub last_hit_by: LastHitBy,
+    pub score: Score,
+    pub kos: Kos,
+    pub stats: MatchStats,
+    pub transform: Transform,
+    pub sprite: Sprite,
+}
+
+impl Default for PlayerBundle {
+    fn default() -> Self {
+        Self {
+            player: Player(1),
+            acceleration: Acceleration(NULL_VECTOR),
+            velocity: Velocity(NULL_VECTOR),
+            mass: Mass(1.0),
+            force_movement: MovementForce {
+                ground: PLAYER_MOVEMENT_FORCE,
+                air: PLAYER_MOVEMENT_FORCE_AIR
+            },
+            force_friction: FrictionForce,
+            force_gravitation: GravitationForce(GRAVTITON_FORCE),
+            on_platform: OnPlatform::default(),
+            special_move_cooldown: Cooldown::default(),
+            special_move: SpecialMove {
+                velocity: SPECIAL_MOVE_VEL,
+                mass: SPECIAL_MOVE_MASS,
+                duration: SPECIAL_MOVE_DURATION,
+                cooldown: 2.0,
+            },
+            jumps: Jumps {
+                velocity: PLAYER_JUMP_VEL,
+                max: 1,
+                left: 1,
+            },
+            fighter: Fighter(0),
+            weight: Weight(1.0),
+            tint: Tint(Color::WHITE),
+            action_state: ActionState::default(),
+            damage: Damage(0.),
+            facing: Facing(1.),
+            hurtbox: Hurtbox::default(),
+            attack_state: AttackState::default(),
+            last_hit_by: LastHitBy::default(),
+            score: Score(0),
+            kos: Kos(0),
+            stats: MatchStats::default(),
+            sprite: Default::default(),
+            transform: Default::default()
+        }
+    }
+}
+
+impl PlayerBundle {
+    /// Applies the stats and `palette` of `character`, the entry `fighter`
+    /// of the [`CharacterRegistry`].
+    pub fn with_character(self, fighter: Fighter, character: &Character, palette: usize) -> Self {
+        Self {
+            tint: Tint(character.palette(palette)),
+            mass: Mass(character.weight),
+            weight: Weight(character.weight),
+            force_movement: MovementForce {
+                ground: Vec3::new(character.ground_speed, 0., 0.),
+                air: Vec3::new(character.air_speed, 0., 0.),
+            },
+            special_move: character.special.clone(),
+            hurtbox: Hurtbox(character.hurtbox),
+            jumps: Jumps {
+                velocity: character.jump_velocity,
+                max: character.jumps,
+                left: character.jumps,
+            },
+            fighter,
+            ..self
+        }
+    }
+}
+
+fn respawn(stage: Res<ActiveStage>,
+           mut query: Query<(Entity, &mut Score, &mut Kos, &mut Transform, &mut LastHitBy,
+                             &mut MatchStats, &Player)>,
+           mut velocities: Query<&mut Velocity>,
+           mut commands: Commands) {
+    let fallen: Vec<(Entity, BlastSide)> = query.iter()
+        .filter_map(|(entity, _, _, tf, ..)| {
+            stage.0.blast_zone.crossed(tf.translation.truncate()).map(|side| (entity, side))
+        })
+        .collect();
+    for (entity, side) in fallen {
+        let Ok((_, mut score, _, mut tf, mut last_hit_by, mut stats, player)) = query.get_mut(entity) else {
+            continue;
+        };
+        score.0 +=1;
+        stats.falls += 1;
+        let position = tf.translation.truncate();
+        tf.translation.x = stage.0.respawn_point.x;
+        tf.translation.y = stage.0.respawn_point.y;
+        if let Ok(mut v) = velocities.get_mut(entity) {
+            v.0 = NULL_VECTOR;
+        }
+        let (player, score, attacker) = (player.0, score.0, last_hit_by.attacker.take());
+        let ko_by = attacker
+            .and_then(|attacker| query.get_mut(attacker).ok())
+            .map(|(_, _, mut kos, _, _, mut stats, attacker)| {
+                kos.0 += 1;
+                stats.kos += 1;
+                attacker.0
+            });
+        match ko_by {
+            Some(attacker) => { info!("Player {} KO'd by player {}, new score: {}", player, attacker, score); }
+            None => {
+                info!("Player {} self-destructed, new score: {}", player, score);
+                if let Ok((.., mut stats, _)) = query.get_mut(entity) {
+                    stats.self_destructs += 1;
+                }
+            }
+        }
+        commands.trigger(KoEvent {
+            player,
+            ko_by,
+            side,
+            position,
+        });
+        commands.trigger(RespawnEvent {
+            player,
+            score,
+            ko_by,
+        });
+    }
+}
+
+fn track_airborne(time: Res<Time>, mut query: Query<(&mut MatchStats, &OnPlatform)>) {
+    for (mut stats, on_platform) in &mut query {
+        if !on_platform.grounded {
+            stats.airborne_secs += time.delta_secs();
+        }
+    }
+}
+
+fn reset_match(mut progress: ResMut<MatchProgress>) {
+    *progress = MatchProgress::default();
+}
+
+/// Sorts results from best to worst. In stock matches and sudden death
+/// fewer falls are better, otherwise more KOs.
+fn rank(results: &mut [(Entity, PlayerResult)], by_falls: bool) {
+    if by_falls {
+        results.sort_by_key(|(_, r)| (r.score, Reverse(r.kos), r.player));
+    }
+    else {
+        results.sort_by_key(|(_, r)| (Reverse(r.kos), r.score, r.player));
+    }
+}
+
+fn tick_match_clock(time: Res<Time>, mut progress: ResMut<MatchProgress>) {
+    progress.elapsed += time.delta_secs();
+}
+
+type MatchPlayer<'a> = (Entity, &'a Player, &'a Fighter, &'a mut Score, &'a Kos, &'a MatchStats,
+                        &'a mut Transform, &'a mut Velocity, &'a mut Damage);
+
+/// Phase of the match, including a change which wasn't applied yet as
+/// several ticks can run before that.
+pub(crate) fn current_phase(phase: &State<MatchPhase>, next_phase: &NextState<MatchPhase>) -> MatchPhase {
+    match next_phase {
+        NextState::Pending(next) | NextState::PendingIfNeq(next) => next.clone(),
+        NextState::Unchanged => phase.get().clone(),
+    }
+}
+
+/// Players who are out are disabled rather than despawned, so a rollback
+/// can bring them back.
+fn check_game_over(mut commands: Commands,
+                   (rules, stage): (Res<MatchRules>, Res<ActiveStage>),
+                   phase: Res<State<MatchPhase>>,
+                   mut progress: ResMut<MatchProgress>,
+                   mut next_phase: ResMut<NextState<MatchPhase>>,
+                   mut query: Query<MatchPlayer>) {
+    if progress.results.is_some() {
+        return;
+    }
+    let stocks = match (current_phase(&phase, &next_phase), rules.win_condition) {
+        (MatchPhase::SuddenDeath, _) => Some(1),
+        (MatchPhase::Regular, WinCondition::Stock(stocks)) => Some(stocks),
+        _ => None,
+    };
+    let mut standings: Vec<(Entity, PlayerResult)> = query.iter()
+        .map(|(entity, player, fighter, score, kos, stats, ..)| (entity, PlayerResult {
+            player: player.0,
+            fighter: *fighter,
+            score: score.0,
+            kos: kos.0,
+            place: 0,
+            stats: stats.clone(),
+        }))
+        .collect();
+    rank(&mut standings, stocks.is_some());
+
+    // Players tied for the win, who go into sudden death.
+    let tied: Vec<(Entity, PlayerResult)> = match stocks {
+        Some(stocks) => {
+            let (out, remaining): (Vec<_>, Vec<_>) = standings.iter().cloned()
+                .partition(|(_, r)| r.score >= stocks);
+            if out.is_empty() {
+                return;
+            }
+            if !remaining.is_empty() {
+                // Everyone who fell out in the same tick shares the worst place.
+                for (entity, result) in out.into_iter().rev() {
+                    commands.entity(entity).insert(Disabled);
+                    progress.eliminated.push(result);
+                }
+                if remaining.len() == 1 {
+                    decide(&mut progress, remaining);
+                }
+                return;
+            }
+            out
+        }
+        None => {
+            let finished = match rules.win_condition {
+                WinCondition::Timed(secs) => progress.elapsed >= secs,
+                WinCondition::FirstTo(kos) => standings.iter().any(|(_, r)| r.kos >= kos),
+                WinCondition::Stock(_) => false,
+            };
+            if !finished {
+                return;
+            }
+            let best = standings[0].1.kos;
+            let leaders: Vec<_> = standings.iter()
+                .filter(|(_, r)| r.kos == best)
+                .cloned()
+                .collect();
+            if leaders.len() == 1 {
+                decide(&mut progress, standings);
+                return;
+            }
+            leaders
+        }
+    };
+
+    info!("Sudden death between {} players!", tied.len());
+    for (entity, result) in standings.iter().rev() {
+        if !tied.iter().any(|(e, _)| e == entity) {
+            commands.entity(*entity).insert(Disabled);
+            progress.eliminated.push(result.clone());
+        }
+    }
+    let mut tied = tied;
+    tied.sort_by_key(|(_, r)| r.player);
+    for (i, (entity, _)) in tied.iter().enumerate() {
+        if let Ok((.., mut score, _, _, mut tf, mut v, mut damage)) = query.get_mut(*entity) {
+            score.0 = 0;
+            damage.0 = SUDDEN_DEATH_DAMAGE;
+            tf.translation = stage.0.spawn_point(i as u32 + 1, tied.len() as u32);
+            v.0 = NULL_VECTOR;
+        }
+    }
+    next_phase.set(MatchPhase::SuddenDeath);
+}
+
+/// Decides the match. `remaining` are the players still in the match, best first.
+fn decide(progress: &mut MatchProgress, remaining: Vec<(Entity, PlayerResult)>) {
+    let results = remaining.into_iter()
+        .map(|(_, result)| result)
+        .chain(progress.eliminated.iter().rev().cloned())
+        .enumerate()
+        .map(|(i, result)| PlayerResult {
+            place: i as u32 + 1,
+            ..result
+        })
+        .collect();
+    progress.results = Some(results);
+}
+
+/// Ends the match once it's decided.
+pub(crate) fn end_match(mut commands: Commands,
+                        progress: Res<MatchProgress>,
+                        mut next_state: ResMut<NextState<GameStates>>) {
+    let Some(results) = &progress.results else {
+        return;
+    };
+    if matches!(*next_state, NextState::Pending(_)) {
+        return;
+    }
+    next_state.set(GameStates::GameOver);
+    for result in results {
+        commands.spawn((DespawnOnExit(GameStates::GameOver), result.clone()));
+    }
+}
+
+pub(crate) fn spawn_players(mut commands: Commands,
+                 player_count: Res<PlayerCount>,
+                 registry: Res<CharacterRegistry>,
+                 selection: Res<CharacterSelection>,
+                 stage: Res<ActiveStage>) {
+    let palettes = selection.palettes(&registry, player_count.0);
+    for player in 1..=player_count.0 {
+        let fighter = selection.pick(player).fighter;
+        let palette = palettes[player as usize - 1];
+        commands.spawn((
+            DespawnOnExit(GameStates::Game),
+            PlayerBundle {
+            player: Player(player),
+            transform: Transform {
+                translation: stage.0.spawn_point(player, player_count.0),
+                scale: Vec2::new(50.0, 50.0).extend(1.0),
+                ..default()
+            },
+            sprite: Sprite {
+                custom_size: Some(Vec2::new(1.,1.)),
+                ..default()
+            },
+            ..Default::default()
+        }.with_character(fighter, registry.get(fighter), palette)));
+    }
+}
+
+fn spawn_stage(mut commands: Commands, stage: Res<ActiveStage>) {
+    for platform in &stage.0.platforms {
+        let mut entity = commands.spawn(
+            (
+                DespawnOnExit(GameStates::Game),
+                Platform,
+                Transform {
+                    translation: platform.position.extend(0.),
+                    scale: platform.size.extend(1.0),
+                    ..default()
+                },
+                Sprite::from_color(rgb(platform.color), Vec2::ONE)
+            ));
+        if platform.one_way {
+            entity.insert(OneWay);
+        }
+        if let Some(path) = &platform.path {
+            entity.insert(MovingPlatform::new(platform.position, path.clone()));
+        }
+        if let Some(timer) = platform.timer {
+            entity.insert(timer);
+        }
+    }
+    for decoration in &stage.0.background.decorations {
+        commands.spawn(
+            (
+                DespawnOnExit(GameStates::Game),
+                Transform {
+                    translation: decoration.position.extend(decoration.depth),
+                    scale: decoration.size.extend(1.0),
+                    ..default()
+                },
+                Sprite::from_color(rgb(decoration.color), Vec2::ONE)
+            ));
+    }
+}
+
+fn select_player_count(
+    mut player_count: ResMut<PlayerCount>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    gamepads: Query<&Gamepad>
+) {
+    let more = keyboard_input.just_pressed(KeyCode::ArrowRight)
+        || gamepads.iter().any(|g| g.just_pressed(GamepadButton::DPadRight));
+    let less = keyboard_input.just_pressed(KeyCode::ArrowLeft)
+        || gamepads.iter().any(|g| g.just_pressed(GamepadButton::DPadLeft));
+    if more && player_count.0 < MAX_PLAYERS {
+        player_count.0 += 1;
+    }
+    if less && player_count.0 > 2 {
+        player_count.0 -= 1;
+    }
+}
+
+fn select_rules(
+    mut rules: ResMut<MatchRules>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    gamepads: Query<&Gamepad>
+) {
+    if keyboard_input.just_pressed(KeyCode::ArrowDown)
+        || gamepads.iter().any(|g| g.just_pressed(GamepadButton::DPadDown)) {
+        rules.win_condition = rules.win_condition.cycle(1);
+    }
+    if keyboard_input.just_pressed(KeyCode::ArrowUp)
+        || gamepads.iter().any(|g| g.just_pressed(GamepadButton::DPadUp)) {
+        rules.win_condition = rules.win_condition.cycle(-1);
+    }
+}
+
+fn start_game(
+    mut next_state: ResMut<NextState<GameStates>>,
+    keyboard_input: Res<ButtonInput<KeyCode>>
+) {
+        if keyboard_input.pressed(KeyCode::Enter) {
+            next_state.set(GameStates::CharacterSelect);
+        }
+        else if keyboard_input.just_pressed(KeyCode::KeyO) {
+            next_state.set(GameStates::Lobby);
+        }
+}
+
+/// CPUs stay ready, they don't pick on their own.
+fn unready_characters(mut selection: ResMut<CharacterSelection>, cpu_players: Res<CpuPlayers>) {
+    for (player, pick) in selection.picks.iter_mut() {
+        pick.ready = cpu_players.0.contains_key(player);
+    }
+}
+
+/// Keys which hand a player slot to the CPU, one per slot.
+const CPU_KEYS: [KeyCode; MAX_PLAYERS as usize] = [
+    KeyCode::Digit1, KeyCode::Digit2, KeyCode::Digit3, KeyCode::Digit4,
+    KeyCode::Digit5, KeyCode::Digit6, KeyCode::Digit7, KeyCode::Digit8,
+];
+
+/// The number keys cycle a player slot through human control and the CPU
+/// difficulties. A CPU keeps the character the slot picked last.
+fn select_cpus(
+    mut cpu_players: ResMut<CpuPlayers>,
+    mut selection: ResMut<CharacterSelection>,
+    player_count: Res<PlayerCount>,
+    keyboard_input: Res<ButtonInput<KeyCode>>
+) {
+    for player in 1..=player_count.0 {
+        if !keyboard_input.just_pressed(CPU_KEYS[player as usize - 1]) {
+            continue;
+        }
+        let next = match cpu_players.0.get(&player) {
+            None => Some(Difficulty::ALL[0]),
+            Some(difficulty) => Difficulty::ALL.iter()
+                .position(|d| d == difficulty)
+                .and_then(|i| Difficulty::ALL.get(i + 1))
+                .copied(),
+        };
+        match next {
+            Some(difficulty) => { cpu_players.0.insert(player, difficulty); }
+            None => { cpu_players.0.remove(&player); }
+        }
+        selection.pick_mut(player).ready = next.is_some();
+    }
+}
+
+/// Every player picks with their own controls: left and right for the
+/// character, up and down for the palette and attack to get ready.
+fn select_characters(
+    mut selection: ResMut<CharacterSelection>,
+    mut next_state: ResMut<NextState<GameStates>>,
+    registry: Res<CharacterRegistry>,
+    player_count: Res<PlayerCount>,
+    cpu_players: Res<CpuPlayers>,
+    slot_actions: Res<SlotActions>,
+    keyboard_input: Res<ButtonInput<KeyCode>>
+) {
+    let characters = registry.characters.len();
+    for player in 1..=player_count.0 {
+        let Some(actions) = slot_actions.0.get(&player) else {
+            continue;
+        };
+        if cpu_players.0.contains_key(&player) {
+            continue;
+        }
+        let pick = selection.pick_mut(player);
+        if actions.just_pressed(Action::Attack) {
+            pick.ready = !pick.ready;
+        }
+        if pick.ready {
+            continue;
+        }
+        if actions.just_pressed(Action::Right) {
+            pick.fighter = Fighter((pick.fighter.0 + 1) % characters);
+            pick.palette = 0;
+        }
+        if actions.just_pressed(Action::Left) {
+            pick.fighter = Fighter((pick.fighter.0 + characters - 1) % characters);
+            pick.palette = 0;
+        }
+        let palettes = registry.get(pick.fighter).palettes.len().max(1);
+        if actions.just_pressed(Action::Down) {
+            pick.palette = (pick.palette + 1) % palettes;
+        }
+        if actions.just_pressed(Action::Up) {
+            pick.palette = (pick.palette + palettes - 1) % palettes;
+        }
+    }
+    if keyboard_input.just_pressed(KeyCode::Escape) {
+        next_state.set(GameStates::Menu);
+    }
+    else if keyboard_input.just_pressed(KeyCode::Enter)
+        || (1..=player_count.0).all(|player| selection.pick(player).ready) {
+        next_state.set(GameStates::StageSelect);
+    }
+}
+
+fn select_stage(
+    mut choice: ResMut<StageChoice>,
+    stage_list: Res<StageList>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    gamepads: Query<&Gamepad>
+) {
+    if keyboard_input.just_pressed(KeyCode::ArrowRight)
+        || gamepads.iter().any(|g| g.just_pressed(GamepadButton::DPadRight)) {
+        *choice = choice.cycle(1, stage_list.stages.len());
+    }
+    if keyboard_input.just_pressed(KeyCode::ArrowLeft)
+        || gamepads.iter().any(|g| g.just_pressed(GamepadButton::DPadLeft)) {
+        *choice = choice.cycle(-1, stage_list.stages.len());
+    }
+}
+
+fn confirm_stage(
+    mut next_state: ResMut<NextState<GameStates>>,
+    mut active_stage: ResMut<ActiveStage>,
+    choice: Res<StageChoice>,
+    stage_list: Res<StageList>,
+    mut rng: ResMut<MatchRng>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    gamepads: Query<&Gamepad>
+) {
+    if keyboard_input.just_pressed(KeyCode::Escape)
+        || gamepads.iter().any(|g| g.just_pressed(GamepadButton::East)) {
+        next_state.set(GameStates::CharacterSelect);
+        return;
+    }
+    if keyboard_input.just_pressed(KeyCode::Enter)
+        || gamepads.iter().any(|g| g.just_pressed(GamepadButton::Start)) {
+        let index = match *choice {
+            StageChoice::Stage(index) => index,
+            StageChoice::Random => rng.0.random_range(0..stage_list.stages.len()),
+        };
+        active_stage.0 = stage_list.stages[index].clone();
+        info!("stage {} selected", active_stage.0.name);
+        next_state.set(GameStates::Game);
+    }
+}
+
+fn resume_match(
+    mut next_state: ResMut<NextState<PauseState>>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    gamepads: Query<&Gamepad>
+) {
+    if keyboard_input.just_pressed(KeyCode::Enter)
+        || gamepads.iter().any(|g| g.just_pressed(GamepadButton::Start)) {
+        next_state.set(PauseState::Running);
+    }
+}
+
+fn rematch(
+    mut next_state: ResMut<NextState<GameStates>>,
+    keyboard_input: Res<ButtonInput<KeyCode>>
+) {
+    if keyboard_input.pressed(KeyCode::KeyR) {
+        next_state.set(GameStates::Game);
+    }
+}
+
+pub struct MatchPlugin;
+impl Plugin for MatchPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ButtonInput<KeyCode>>();
+
+        // Menu systems
+        app.init_resource::<PlayerCount>();
+        app.init_resource::<MatchRules>();
+        app.add_systems(FixedUpdate, start_game.in_set(MenuSet));
+        app.add_systems(Update, (select_player_count, select_rules)
+                        .run_if(in_state(GameStates::Menu)));
+        app.configure_sets(FixedUpdate, MenuSet.run_if(in_state(GameStates::Menu)));
+
+        // Character select systems
+        app.init_resource::<CharacterSelection>();
+        app.add_systems(OnEnter(GameStates::CharacterSelect), unready_characters);
+        app.add_systems(Update, (select_cpus, select_characters)
+                        .chain()
+                        .after(read_slot_input)
+                        .run_if(in_state(GameStates::CharacterSelect)));
+
+        // Stage select systems
+        app.init_resource::<StageList>();
+        app.init_resource::<StageChoice>();
+        app.add_systems(Update, (select_stage, confirm_stage)
+                        .chain()
+                        .run_if(in_state(GameStates::StageSelect)));
+
+        // Game systems
+        app.init_resource::<MatchProgress>();
+        app.init_resource::<CharacterRegistry>();
+        app.init_resource::<ActiveStage>();
+        app.add_systems(OnEnter(GameStates::Game), (spawn_players, spawn_stage, reset_match));
+        app.add_systems(FixedUpdate, (
+            tick_match_clock,
+            track_airborne,
+            respawn,
+            check_game_over,
+            // Online matches end from the netcode, once the result is confirmed.
+            end_match.run_if(not(resource_exists::<NetSession>)),
+        ).chain().in_set(GameSystems::Rules).in_set(GameSet));
+        app.configure_sets(FixedUpdate, (
+            GameSystems::Input,
+            GameSystems::Movement,
+            GameSystems::Forces,
+            GameSystems::Actions,
+            GameSystems::Integrate,
+            GameSystems::Collide,
+            GameSystems::Hits,
+            GameSystems::Rules,
+            GameSystems::Checksum,
+        ).chain());
+        app.configure_sets(FixedUpdate, GameSet
+                           .run_if(in_state(GameStates::Game))
+                           .run_if(in_state(PauseState::Running)));
+        app.add_systems(Update, resume_match.run_if(in_state(PauseState::Paused)));
+
+        // GameOver systems
+        app.add_systems(FixedUpdate, rematch.in_set(GameOverSet));
+        app.configure_sets(FixedUpdate, GameOverSet.run_if(in_state(GameStates::GameOver)));
+
+        app.insert_state(GameStates::Menu);
+        app.add_sub_state::<PauseState>();
+        app.add_sub_state::<MatchPhase>();
+    }
+}
@@ -0,0 +1,425 @@
+use bevy::input::gamepad::GamepadConnectionEvent;
+use bevy::platform::collections::HashMap;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::character::{SpecialMove, Weight};
+use crate::collision::OnPlatform;
+use crate::cpu::Cpu;
+use crate::physics::{Acceleration, Mass, MovementForce, Velocity};
+use crate::game_match::{MatchStats, PlayerCount, MAX_PLAYERS};
+use crate::netcode::NetSession;
+use crate::replay::ReplayPlayback;
+use crate::{GameSet, GameStates, GameSystems, PauseState, Player, NULL_VECTOR};
+
+pub const PLAYER_JUMP_VEL: f32 = 2560.;
+pub const SPECIAL_MOVE_MASS: f32 = 4.0;
+pub const SPECIAL_MOVE_VEL: f32 = 3200.;
+/// How long the special move keeps the increased mass, in seconds.
+pub const SPECIAL_MOVE_DURATION: f32 = 0.5;
+
+/// Special move cooldown, counted in `FixedUpdate` ticks so it doesn't
+/// depend on frame timing.
+#[derive(Component, Clone)]
+pub struct Cooldown {
+    /// Ticks since the special move was used.
+    pub ticks: u32,
+    pub charge: bool,
+}
+
+impl Default for Cooldown {
+    fn default() -> Self {
+        Cooldown { ticks: 0, charge: true }
+    }
+}
+
+/// Jumps a player has left before landing again.
+#[derive(Component, Clone)]
+pub struct Jumps {
+    pub velocity: f32,
+    pub max: u32,
+    pub left: u32,
+}
+
+/// Abstract player actions, independent of the input device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Left,
+    Right,
+    Up,
+    Down,
+    Jump,
+    Special,
+    Attack,
+    Shield,
+}
+
+impl Action {
+    pub const ALL: [Action; 8] = [
+        Action::Left,
+        Action::Right,
+        Action::Up,
+        Action::Down,
+        Action::Jump,
+        Action::Special,
+        Action::Attack,
+        Action::Shield,
+    ];
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InputBinding {
+    Key(KeyCode),
+    GamepadButton(GamepadButton),
+}
+
+/// Radial deadzone of the analog stick, as a fraction of its full range.
+pub const DEFAULT_STICK_DEADZONE: f32 = 0.2;
+
+/// Bindings of one player slot.
+#[derive(Debug, Clone)]
+pub struct SlotBindings {
+    /// Gamepad whose buttons and left stick are read for this slot.
+    pub gamepad: Option<Entity>,
+    pub stick_deadzone: f32,
+    pub bindings: Vec<(Action, InputBinding)>,
+}
+
+impl Default for SlotBindings {
+    fn default() -> Self {
+        Self {
+            gamepad: None,
+            stick_deadzone: DEFAULT_STICK_DEADZONE,
+            bindings: Vec::new(),
+        }
+    }
+}
+
+impl SlotBindings {
+    pub fn bind(&mut self, action: Action, binding: InputBinding) -> &mut Self {
+        self.bindings.push((action, binding));
+        self
+    }
+
+    pub fn bindings_for(&self, action: Action) -> impl Iterator<Item = &InputBinding> {
+        self.bindings.iter().filter(move |(a, _)| *a == action).map(|(_, b)| b)
+    }
+}
+
+/// Maps the actions of each player slot to keys and gamepad buttons.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct InputMap {
+    pub slots: HashMap<u32, SlotBindings>,
+}
+
+impl InputMap {
+    pub fn slot(&self, player: u32) -> Option<&SlotBindings> {
+        self.slots.get(&player)
+    }
+
+    pub fn slot_mut(&mut self, player: u32) -> &mut SlotBindings {
+        self.slots.entry(player).or_default()
+    }
+
+    /// Default keyboard layout: arrow keys for player 1 and WASD for player 2,
+    /// plus the standard gamepad layout for every player slot.
+    pub fn with_default_bindings() -> Self {
+        use InputBinding::{GamepadButton as Button, Key};
+        let mut map = InputMap::default();
+        let keys = [
+            (1, [KeyCode::ArrowLeft, KeyCode::ArrowRight, KeyCode::ArrowUp, KeyCode::ArrowDown,
+                 KeyCode::ArrowUp, KeyCode::ShiftRight, KeyCode::ControlRight, KeyCode::Slash]),
+            (2, [KeyCode::KeyA, KeyCode::KeyD, KeyCode::KeyW, KeyCode::KeyS,
+                 KeyCode::KeyW, KeyCode::ShiftLeft, KeyCode::KeyF, KeyCode::KeyG]),
+        ];
+        for (player, keys) in keys {
+            let slot = map.slot_mut(player);
+            for (action, key) in Action::ALL.into_iter().zip(keys) {
+                slot.bind(action, Key(key));
+            }
+        }
+        for player in 1..=MAX_PLAYERS {
+            map.slot_mut(player).bind(Action::Left, Button(GamepadButton::DPadLeft))
+                .bind(Action::Right, Button(GamepadButton::DPadRight))
+                .bind(Action::Up, Button(GamepadButton::DPadUp))
+                .bind(Action::Down, Button(GamepadButton::DPadDown))
+                .bind(Action::Jump, Button(GamepadButton::North))
+                .bind(Action::Special, Button(GamepadButton::South))
+                .bind(Action::Attack, Button(GamepadButton::West))
+                .bind(Action::Shield, Button(GamepadButton::RightTrigger));
+        }
+        map
+    }
+}
+
+/// Actions held by a player in the current and the previous tick, plus the
+/// analog stick position.
+#[derive(Component, Debug, Clone, Copy, Default, PartialEq)]
+pub struct ActionState {
+    pressed: u8,
+    previous: u8,
+    stick: Vec2,
+}
+
+impl ActionState {
+    pub fn pressed(&self, action: Action) -> bool {
+        self.pressed & action.bit() != 0
+    }
+
+    pub fn just_pressed(&self, action: Action) -> bool {
+        self.pressed(action) && self.previous & action.bit() == 0
+    }
+
+    pub fn press(&mut self, action: Action) {
+        self.pressed |= action.bit();
+    }
+
+    pub fn release(&mut self, action: Action) {
+        self.pressed &= !action.bit();
+    }
+
+    /// All held actions as a bit set, one bit per [`Action`].
+    pub fn bits(&self) -> u8 {
+        self.pressed
+    }
+
+    pub fn set_bits(&mut self, bits: u8) {
+        self.pressed = bits;
+    }
+
+    /// Analog stick position after the deadzone, with a length of at most 1.
+    pub fn stick(&self) -> Vec2 {
+        self.stick
+    }
+
+    pub fn set_stick(&mut self, stick: Vec2) {
+        self.stick = stick.clamp_length_max(1.);
+    }
+
+    /// Starts a new tick: the held actions become the previous ones.
+    pub fn advance(&mut self) {
+        self.previous = self.pressed;
+    }
+}
+
+/// Applies a radial deadzone and rescales the rest of the range to `0..=1`.
+pub fn apply_deadzone(stick: Vec2, deadzone: f32) -> Vec2 {
+    let length = stick.length();
+    if length <= deadzone {
+        return Vec2::ZERO;
+    }
+    let scaled = ((length - deadzone) / (1. - deadzone)).min(1.);
+    stick / length * scaled
+}
+
+fn binding_pressed(binding: &InputBinding,
+                   keyboard_input: &ButtonInput<KeyCode>,
+                   gamepad: Option<&Gamepad>) -> bool {
+    match binding {
+        InputBinding::Key(key) => keyboard_input.pressed(*key),
+        InputBinding::GamepadButton(button) => gamepad.is_some_and(|g| g.pressed(*button)),
+    }
+}
+
+/// Reads the keyboard and gamepad state of slot `player` into `action_state`.
+pub(crate) fn read_slot(player: u32,
+                        input_map: &InputMap,
+                        keyboard_input: &ButtonInput<KeyCode>,
+                        gamepads: &Query<&Gamepad>,
+                        action_state: &mut ActionState) {
+    action_state.advance();
+    let mut bits = 0;
+    let mut stick = Vec2::ZERO;
+    if let Some(slot) = input_map.slot(player) {
+        let gamepad = slot.gamepad.and_then(|e| gamepads.get(e).ok());
+        for action in Action::ALL {
+            if slot.bindings_for(action)
+                .any(|b| binding_pressed(b, keyboard_input, gamepad)) {
+                bits |= action.bit();
+            }
+        }
+        if let Some(gamepad) = gamepad {
+            stick = apply_deadzone(gamepad.left_stick(), slot.stick_deadzone);
+        }
+    }
+    action_state.set_bits(bits);
+    action_state.set_stick(stick);
+}
+
+/// Reads keyboard and gamepad state into the [`ActionState`] of each player
+/// not controlled by the CPU.
+pub fn read_local_input(keyboard_input: Res<ButtonInput<KeyCode>>,
+                    input_map: Res<InputMap>,
+                    gamepads: Query<&Gamepad>,
+                    mut query: Query<(&mut ActionState, &Player), Without<Cpu>>) {
+    for (mut action_state, player) in &mut query {
+        read_slot(player.0, &input_map, &keyboard_input, &gamepads, &mut action_state);
+    }
+}
+
+/// Actions of every player slot outside of a match, so each player can
+/// navigate menus with their own controls.
+#[derive(Resource, Default)]
+pub struct SlotActions(pub HashMap<u32, ActionState>);
+
+pub fn read_slot_input(keyboard_input: Res<ButtonInput<KeyCode>>,
+                   input_map: Res<InputMap>,
+                   player_count: Res<PlayerCount>,
+                   gamepads: Query<&Gamepad>,
+                   mut slot_actions: ResMut<SlotActions>) {
+    for player in 1..=player_count.0 {
+        let action_state = slot_actions.0.entry(player).or_default();
+        read_slot(player, &input_map, &keyboard_input, &gamepads, action_state);
+    }
+}
+
+/// Assigns newly connected gamepads to the first player slot without one and
+/// pauses a running match whenever a gamepad is plugged in or out.
+fn assign_gamepads(mut connections: MessageReader<GamepadConnectionEvent>,
+                   mut input_map: ResMut<InputMap>,
+                   game_state: Res<State<GameStates>>,
+                   mut next_pause_state: ResMut<NextState<PauseState>>) {
+    for connection in connections.read() {
+        if connection.connected() {
+            let mut players: Vec<u32> = input_map.slots.keys().copied().collect();
+            players.sort();
+            match players.into_iter().find(|p| input_map.slots[p].gamepad.is_none()) {
+                Some(player) => {
+                    info!("gamepad {} assigned to player {}", connection.gamepad, player);
+                    input_map.slot_mut(player).gamepad = Some(connection.gamepad);
+                }
+                None => { info!("no free player slot for gamepad {}", connection.gamepad); }
+            }
+        }
+        else {
+            for slot in input_map.slots.values_mut() {
+                if slot.gamepad == Some(connection.gamepad) {
+                    slot.gamepad = None;
+                }
+            }
+        }
+        if *game_state.get() == GameStates::Game {
+            next_pause_state.set(PauseState::Paused);
+        }
+    }
+}
+
+fn jump(mut query: Query<(&mut Velocity, &mut Jumps, &ActionState, &OnPlatform)>) {
+    for (mut v, mut jumps, action_state, on_platform) in &mut query {
+        if on_platform.grounded {
+            jumps.left = jumps.max;
+        }
+        if action_state.just_pressed(Action::Jump) && jumps.left > 0 {
+            jumps.left -= 1;
+            v.0.y = v.0.y.max(0.) + jumps.velocity;
+        }
+    }
+}
+
+/// Movement direction of a player. The analog stick keeps its magnitude,
+/// digital directions are normalized.
+pub fn get_movement(action_state: &ActionState) -> Vec3 {
+    if action_state.stick() != Vec2::ZERO {
+        return action_state.stick().extend(0.);
+    }
+    let mut direction = NULL_VECTOR;
+    if action_state.pressed(Action::Left) {
+        direction.x -= 1.0;
+    }
+    if action_state.pressed(Action::Right) {
+        direction.x += 1.0;
+    }
+    if action_state.pressed(Action::Up) {
+        direction.y += 1.0;
+    }
+    if action_state.pressed(Action::Down) {
+        direction.y -= 1.0;
+    }
+    direction.normalize_or(NULL_VECTOR)
+}
+
+fn movement_force(mut query: Query<(&mut Acceleration, &MovementForce,
+                                    &ActionState, &OnPlatform)>) {
+    for (mut accel, mf_accel, action_state, on_platform) in &mut query {
+
+        let direction = get_movement(action_state);
+
+        if on_platform.grounded {
+            accel.0 += direction * mf_accel.ground;
+        }
+        else {
+            accel.0 += direction * mf_accel.air;
+        }
+    }
+}
+
+type SpecialMoveQuery<'a> = (&'a mut Velocity,
+                              &'a mut Cooldown,
+                              &'a mut Mass,
+                              &'a SpecialMove,
+                              &'a Weight,
+                              &'a mut MatchStats,
+                              &'a ActionState,
+                              &'a Player);
+
+fn special_move(
+    mut query: Query<SpecialMoveQuery>,
+    time: Res<Time<Fixed>>,
+) {
+    let ticks = |secs: f32| (secs / time.timestep().as_secs_f32()).round() as u32;
+    for (mut v, mut cooldown, mut mass, special, weight, mut stats, action_state, player) in &mut query {
+        if !cooldown.charge {
+            cooldown.ticks += 1;
+            if cooldown.ticks >= ticks(special.duration) {
+                mass.0 = weight.0;
+            }
+            if cooldown.ticks >= ticks(special.cooldown) {
+                cooldown.charge = true;
+                info!("cooldown charge restored");
+                cooldown.ticks = 0;
+            }
+        }
+        if cooldown.charge && action_state.pressed(Action::Special) {
+            info!("player {} special move!", player.0);
+            let direction = get_movement(action_state);
+            let boost = if direction.x == 0. && direction.y == 0.0 {
+                v.0.normalize_or(NULL_VECTOR)
+            }
+            else {
+                direction.normalize_or(NULL_VECTOR)
+            };
+            v.0 += special.velocity * boost;
+            cooldown.charge = false;
+            mass.0 = special.mass;
+            stats.special_moves += 1;
+        }
+    }
+}
+
+pub struct InputPlugin;
+impl Plugin for InputPlugin {
+    fn build(&self, app: &mut App) {
+        // Headless apps don't add bevy's input plugin, so make sure the
+        // keyboard state exists for the gameplay systems to read.
+        app.init_resource::<ButtonInput<KeyCode>>();
+        app.insert_resource(InputMap::with_default_bindings());
+        app.add_message::<GamepadConnectionEvent>();
+        app.add_systems(PreUpdate, assign_gamepads);
+        app.init_resource::<SlotActions>();
+        app.add_systems(Update, read_slot_input
+            .run_if(in_state(GameStates::CharacterSelect).or(in_state(GameStates::Lobby))));
+        app.add_systems(FixedUpdate, (
+            read_local_input
+                .run_if(not(resource_exists::<ReplayPlayback>))
+                .run_if(not(resource_exists::<NetSession>))
+                .in_set(GameSystems::Input),
+            movement_force.in_set(GameSystems::Movement),
+            (jump, special_move).chain().in_set(GameSystems::Actions),
+        ).in_set(GameSet));
+    }
+}
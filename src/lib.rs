@@ -0,0 +1,151 @@
+//! Platform fighter game logic.
+//!
+//! The game is split into plugins which are composed by [`GamePlugin`].
+//! The simulation itself only needs [`MatchPlugin`], [`PhysicsPlugin`],
+//! [`CollisionPlugin`], [`PlatformPlugin`], [`CombatPlugin`],
+//! [`InputPlugin`] and [`DeterminismPlugin`], so it can run in a headless `App` without a window:
+//!
+//! ```no_run
+//! use bevy::prelude::*;
+//! use bevy::state::app::StatesPlugin;
+//! use platform_fighter::*;
+//!
+//! App::new()
+//!     .add_plugins((MinimalPlugins, StatesPlugin))
+//!     .add_plugins((MatchPlugin, PhysicsPlugin, CollisionPlugin, PlatformPlugin, CombatPlugin,
+//!                   InputPlugin, DeterminismPlugin))
+//!     .run();
+//! ```
+
+use bevy::prelude::*;
+
+pub mod audio;
+pub mod character;
+pub mod collision;
+pub mod combat;
+pub mod cpu;
+pub mod determinism;
+pub mod env;
+pub mod game_match;
+pub mod input;
+pub mod lobby;
+pub mod netcode;
+pub mod online;
+pub mod physics;
+pub mod platform;
+pub mod replay;
+pub mod sim;
+pub mod stage;
+pub mod ui;
+
+pub use audio::AudioPlugin;
+pub use character::{Character, CharacterPlugin, CharacterRegistry, CharacterSelection, Fighter};
+pub use collision::{CollisionPlugin, OnPlatform, OneWay, Platform};
+pub use combat::{CombatPlugin, Damage, HitEvent, Hitbox, Hurtbox, MoveSet};
+pub use cpu::{Cpu, CpuPlayers, CpuPlugin, Difficulty};
+pub use determinism::{DeterminismPlugin, MatchRng, MatchSeed, StateChecksum};
+pub use game_match::{
+    KoEvent, Kos, MatchPlugin, MatchRules, MatchStats, Player, PlayerBundle, PlayerResult, RespawnEvent,
+    Score, WinCondition,
+};
+pub use input::{Action, ActionState, Cooldown, InputMap, InputPlugin};
+pub use netcode::{NetSession, NetcodePlugin};
+pub use online::{OnlinePlugin, OnlineSettings};
+pub use physics::{
+    Acceleration, FrictionForce, GravitationForce, Mass, MovementForce, PhysicsPlugin, Substeps,
+    Velocity,
+};
+pub use platform::{MovingPlatform, PlatformPlugin, PlatformTimer};
+pub use replay::{Replay, ReplayPlugin};
+pub use stage::{ActiveStage, BlastSide, Stage, StageChoice, StageList, StagePlugin};
+pub use ui::{ClockDisplay, DamageDisplay, GameOverText, ScoreDisplay, UiPlugin};
+
+pub const NULL_VECTOR: Vec3 = Vec3::new(0.0, 0.0, 0.0);
+
+#[derive(States, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum GameStates {
+    Menu,
+    CharacterSelect,
+    StageSelect,
+    Lobby,
+    Game,
+    GameOver,
+}
+
+/// Whether a running match is paused, e.g. because a gamepad was plugged
+/// in or out.
+#[derive(SubStates, Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[source(GameStates = GameStates::Game)]
+pub enum PauseState {
+    #[default]
+    Running,
+    Paused,
+}
+
+/// Whether a match is still being played by its rules, or whether the
+/// players tied for the win play sudden death.
+#[derive(SubStates, Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[source(GameStates = GameStates::Game)]
+pub enum MatchPhase {
+    #[default]
+    Regular,
+    SuddenDeath,
+}
+
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MenuSet;
+
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GameSet;
+
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GameOverSet;
+
+/// Order of the steps of one game tick in `FixedUpdate`.
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum GameSystems {
+    /// Devices and scripts are read into each player's `ActionState`.
+    Input,
+    /// Player movement input is turned into forces.
+    Movement,
+    /// Environmental forces like friction and gravitation.
+    Forces,
+    /// Jumps and special moves which change the velocity directly.
+    Actions,
+    /// Velocities are applied to the transforms. Runs the
+    /// [`PhysicsSubstep`](physics::PhysicsSubstep) schedule, where this set
+    /// and `Collide` take turns for each substep.
+    Integrate,
+    /// Collisions between players and platforms, in the
+    /// [`PhysicsSubstep`](physics::PhysicsSubstep) schedule.
+    Collide,
+    /// Attack hitboxes are checked against hurtboxes.
+    Hits,
+    /// Respawns and scoring.
+    Rules,
+    /// The state after the tick is hashed.
+    Checksum,
+}
+
+pub struct GamePlugin;
+impl Plugin for GamePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins((
+            MatchPlugin,
+            PhysicsPlugin,
+            CollisionPlugin,
+            PlatformPlugin,
+            CombatPlugin,
+            InputPlugin,
+            CpuPlugin,
+            UiPlugin,
+            AudioPlugin,
+            StagePlugin,
+            CharacterPlugin,
+            DeterminismPlugin,
+            ReplayPlugin,
+            NetcodePlugin,
+            OnlinePlugin,
+        ));
+    }
+}
@@ -0,0 +1,230 @@
+//! Protocol between the game and `platform-fighter-server`, which hosts
+//! lobbies of two players and relays the packets of peers which can't reach
+//! each other directly.
+//!
+//! Everything goes over one UDP socket per client. The first byte of a
+//! datagram tells what it is: a [`ClientMessage`] or [`ServerMessage`] as
+//! JSON, or a netcode packet sent to the peer directly or through the server.
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::game_match::WinCondition;
+use crate::netcode::Transport;
+
+pub const DEFAULT_SERVER_ADDR: &str = "127.0.0.1:7700";
+/// Players in a lobby.
+pub const LOBBY_SIZE: usize = 2;
+/// Seconds between heartbeats, clients which miss a few are dropped.
+pub const HEARTBEAT_SECS: f32 = 1.;
+
+const LOBBY_TAG: u8 = 0;
+/// Netcode packet which the server forwards to the other player of the lobby.
+const RELAY_TAG: u8 = 1;
+/// Netcode packet sent straight to the peer.
+const DIRECT_TAG: u8 = 2;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ClientMessage {
+    ListLobbies,
+    Host { win_condition: WinCondition, stage: String },
+    Join { lobby: u32 },
+    Leave,
+    Pick { fighter: usize, palette: usize, ready: bool },
+    /// Only the host can change the stage.
+    Stage { stage: String },
+    Heartbeat,
+    /// Acknowledges [`ServerMessage::Start`].
+    Started,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ServerMessage {
+    Lobbies { lobbies: Vec<LobbySummary> },
+    /// The lobby the client is in changed, or the client joined it.
+    Lobby { lobby: LobbyState, player: u32 },
+    /// Both players are ready, the match starts. Sent again on each message
+    /// from the client until it answers with [`ClientMessage::Started`].
+    Start { seed: u64, player: u32, peer: SocketAddr },
+    Error { message: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LobbySummary {
+    pub id: u32,
+    pub players: usize,
+    pub win_condition: WinCondition,
+    pub stage: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LobbyState {
+    pub id: u32,
+    pub win_condition: WinCondition,
+    /// Name of the stage in the `StageList`.
+    pub stage: String,
+    /// Player `n` is `members[n - 1]`, the host is player 1.
+    pub members: Vec<LobbyMember>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct LobbyMember {
+    pub fighter: usize,
+    pub palette: usize,
+    pub ready: bool,
+}
+
+pub fn encode<T: Serialize>(message: &T) -> Vec<u8> {
+    let mut bytes = vec![LOBBY_TAG];
+    bytes.extend(serde_json::to_vec(message).expect("lobby messages serialize"));
+    bytes
+}
+
+pub fn decode<T: for<'de> Deserialize<'de>>(bytes: &[u8]) -> Option<T> {
+    match bytes.split_first() {
+        Some((&LOBBY_TAG, json)) => serde_json::from_slice(json).ok(),
+        _ => None,
+    }
+}
+
+/// Whether `bytes` is a netcode packet for the server to relay.
+pub fn is_relayed(bytes: &[u8]) -> bool {
+    bytes.first() == Some(&RELAY_TAG)
+}
+
+/// Sends netcode packets to the peer directly, and through the server
+/// until the peer reports that direct packets arrive. After that it keeps
+/// sending heartbeats, so the server still relays the peer's packets in
+/// case only this direction works directly.
+pub struct LobbyTransport {
+    socket: UdpSocket,
+    server: SocketAddr,
+    peer: SocketAddr,
+    /// Whether packets from the peer arrived directly.
+    heard_direct: bool,
+    /// Whether the peer reported that our packets arrive directly.
+    peer_heard_direct: bool,
+    /// When the server was last sent anything.
+    server_contact: Instant,
+}
+
+impl LobbyTransport {
+    /// `socket` is the one connected to the lobby server, so the server
+    /// knows where to relay packets to.
+    pub fn new(socket: UdpSocket, server: SocketAddr, peer: SocketAddr) -> Self {
+        LobbyTransport {
+            socket,
+            server,
+            peer,
+            heard_direct: false,
+            peer_heard_direct: false,
+            server_contact: Instant::now(),
+        }
+    }
+
+    fn send_to(&self, tag: u8, packet: &[u8], to: SocketAddr) {
+        let mut bytes = vec![tag, self.heard_direct as u8];
+        bytes.extend(packet);
+        if let Err(e) = self.socket.send_to(&bytes, to) {
+            warn!("could not send to {}: {}", to, e);
+        }
+    }
+}
+
+impl Transport for LobbyTransport {
+    fn send(&mut self, packet: &[u8]) {
+        self.send_to(DIRECT_TAG, packet, self.peer);
+        if !self.peer_heard_direct {
+            self.send_to(RELAY_TAG, packet, self.server);
+            self.server_contact = Instant::now();
+        }
+        else if self.server_contact.elapsed() >= Duration::from_secs_f32(HEARTBEAT_SECS) {
+            if let Err(e) = self.socket.send_to(&encode(&ClientMessage::Heartbeat), self.server) {
+                warn!("could not send to {}: {}", self.server, e);
+            }
+            self.server_contact = Instant::now();
+        }
+    }
+
+    fn receive(&mut self) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        let mut buffer = [0; 2048];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => { return packets; }
+                Err(e) => {
+                    // E.g. the peer's port being closed, reported by some systems.
+                    debug!("could not receive: {}", e);
+                    return packets;
+                }
+            };
+            match buffer[..len] {
+                [DIRECT_TAG, peer_heard_direct, ..] if from == self.peer => {
+                    self.heard_direct = true;
+                    self.peer_heard_direct |= peer_heard_direct != 0;
+                }
+                [RELAY_TAG, peer_heard_direct, ..] if from == self.server => {
+                    self.peer_heard_direct |= peer_heard_direct != 0;
+                }
+                _ => { continue; }
+            }
+            packets.push(buffer[2..len].to_vec());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_round_trip() {
+        let message = ClientMessage::Host { win_condition: WinCondition::Stock(3), stage: "Tower".into() };
+        let decoded = decode::<ClientMessage>(&encode(&message));
+        assert!(matches!(decoded, Some(ClientMessage::Host { win_condition: WinCondition::Stock(3), ref stage })
+            if stage == "Tower"), "{:?}", decoded);
+
+        let peer: SocketAddr = "127.0.0.1:7701".parse().unwrap();
+        let message = ServerMessage::Start { seed: 42, player: 2, peer };
+        let decoded = decode::<ServerMessage>(&encode(&message));
+        assert!(matches!(decoded, Some(ServerMessage::Start { seed: 42, player: 2, peer: p }) if p == peer),
+                "{:?}", decoded);
+    }
+
+    #[test]
+    fn netcode_packets_are_not_messages() {
+        assert!(decode::<ClientMessage>(&[RELAY_TAG, 0, 1, 2]).is_none());
+        assert!(decode::<ClientMessage>(&[DIRECT_TAG, 0, 1, 2]).is_none());
+        assert!(decode::<ClientMessage>(&[]).is_none());
+        assert!(is_relayed(&[RELAY_TAG, 0]));
+        assert!(!is_relayed(&encode(&ClientMessage::Heartbeat)));
+    }
+
+    #[test]
+    fn direct_transport_keeps_sending_heartbeats() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut transport = LobbyTransport::new(UdpSocket::bind("127.0.0.1:0").unwrap(),
+                                                server.local_addr().unwrap(),
+                                                peer.local_addr().unwrap());
+        transport.send(&[1, 2, 3]);
+        let mut buffer = [0; 64];
+        let (len, _) = server.recv_from(&mut buffer).unwrap();
+        assert!(is_relayed(&buffer[..len]));
+
+        // Once the peer hears us directly, the server only gets heartbeats.
+        transport.peer_heard_direct = true;
+        transport.server_contact -= Duration::from_secs_f32(HEARTBEAT_SECS);
+        transport.send(&[1, 2, 3]);
+        transport.send(&[1, 2, 3]);
+        let (len, _) = server.recv_from(&mut buffer).unwrap();
+        assert!(matches!(decode(&buffer[..len]), Some(ClientMessage::Heartbeat)));
+        server.set_nonblocking(true).unwrap();
+        assert!(server.recv_from(&mut buffer).is_err(), "one heartbeat per interval");
+    }
+}
@@ -0,0 +1,722 @@
+//! Rollback netcode for two-player online matches, in the style of GGPO.
+//!
+//! Each peer simulates every tick right away, predicting that the remote
+//! player keeps holding what they held last. Whenever a remote input arrives
+//! which differs from the prediction, the gameplay state is restored from the
+//! snapshot taken before that tick and the `FixedUpdate` chain is run again
+//! up to the current tick. Peers exchange their inputs and the
+//! [`StateChecksum`] of confirmed ticks over a [`Transport`], so desyncs are
+//! detected.
+//!
+//! Only gameplay state is rolled back. Players who are out are disabled
+//! rather than despawned and the match phase is part of the snapshot, so
+//! eliminations and sudden death can be undone, but a decided match only
+//! ends once all inputs up to its last tick are confirmed. Observers like
+//! sounds may fire again during resimulation.
+
+use bevy::ecs::entity_disabling::Disabled;
+use bevy::ecs::query::{Allow, QueryData};
+use bevy::prelude::*;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+
+use crate::collision::OnPlatform;
+use crate::combat::{AttackState, Facing, Hitbox, LastHitBy, HITBOX_COLOR};
+use crate::determinism::{compute_checksum, MatchRng, StateChecksum};
+use crate::game_match::{current_phase, end_match, MatchProgress, MatchStats};
+use crate::input::{read_slot, Cooldown, InputMap, Jumps};
+use crate::physics::{Acceleration, Mass, Velocity};
+use crate::{ActionState, Damage, GameSet, GameStates, GameSystems, Kos, MatchPhase, Player, Score};
+
+/// How many ticks a peer may run ahead of the last confirmed remote input
+/// before it waits for the remote peer.
+pub const MAX_PREDICTION: u32 = 8;
+/// Default number of ticks local inputs are delayed by, which hides small
+/// latencies without rollbacks.
+pub const DEFAULT_INPUT_DELAY: u32 = 2;
+/// Most inputs sent in one packet.
+const MAX_PACKET_INPUTS: usize = 64;
+/// How many local checksums are kept around to compare with the remote ones.
+const CHECKSUM_HISTORY: u32 = 256;
+
+/// Unreliable, unordered delivery of packets to the remote peer.
+pub trait Transport: Send + Sync + 'static {
+    fn send(&mut self, packet: &[u8]);
+    /// Packets which arrived since the last call, called once per tick.
+    fn receive(&mut self) -> Vec<Vec<u8>>;
+}
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+    remote: SocketAddr,
+}
+
+impl UdpTransport {
+    pub fn bind(local: SocketAddr, remote: SocketAddr) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(local)?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpTransport { socket, remote })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, packet: &[u8]) {
+        if let Err(e) = self.socket.send_to(packet, self.remote) {
+            warn!("could not send to {}: {}", self.remote, e);
+        }
+    }
+
+    fn receive(&mut self) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        let mut buffer = [0; 2048];
+        loop {
+            match self.socket.recv_from(&mut buffer) {
+                Ok((len, from)) if from == self.remote => { packets.push(buffer[..len].to_vec()); }
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => { return packets; }
+                Err(e) => {
+                    warn!("could not receive from {}: {}", self.remote, e);
+                    return packets;
+                }
+            }
+        }
+    }
+}
+
+/// Packets in flight, with the tick of the receiver they arrive at.
+type Link = Arc<Mutex<Vec<(u32, Vec<u8>)>>>;
+
+/// In-process transport with artificial latency and packet loss, to test
+/// rollbacks without a network.
+pub struct LoopbackTransport {
+    outgoing: Link,
+    incoming: Link,
+    tick: u32,
+    /// Ticks until a packet arrives.
+    latency: u32,
+    /// Chance of a packet getting lost, from 0 to 1.
+    loss: f32,
+    rng: ChaCha8Rng,
+}
+
+impl LoopbackTransport {
+    /// Two connected ends, losing packets randomly based on `seed`.
+    pub fn pair(latency: u32, loss: f32, seed: u64) -> (LoopbackTransport, LoopbackTransport) {
+        let a_to_b = Link::default();
+        let b_to_a = Link::default();
+        let end = |outgoing: &Link, incoming: &Link, seed| LoopbackTransport {
+            outgoing: outgoing.clone(),
+            incoming: incoming.clone(),
+            tick: 0,
+            latency,
+            loss,
+            rng: ChaCha8Rng::seed_from_u64(seed),
+        };
+        (end(&a_to_b, &b_to_a, seed), end(&b_to_a, &a_to_b, seed.wrapping_add(1)))
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn send(&mut self, packet: &[u8]) {
+        if self.rng.random::<f32>() >= self.loss {
+            self.outgoing.lock().unwrap().push((self.tick + self.latency, packet.to_vec()));
+        }
+    }
+
+    fn receive(&mut self) -> Vec<Vec<u8>> {
+        self.tick += 1;
+        let mut incoming = self.incoming.lock().unwrap();
+        let (arrived, in_flight) = incoming.drain(..).partition(|(tick, _)| *tick <= self.tick);
+        *incoming = in_flight;
+        arrived.into_iter().map(|(_, packet)| packet).collect()
+    }
+}
+
+/// What a player held during one tick.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FrameInput {
+    pub bits: u8,
+    pub stick: Vec2,
+}
+
+impl From<&ActionState> for FrameInput {
+    fn from(action_state: &ActionState) -> Self {
+        FrameInput { bits: action_state.bits(), stick: action_state.stick() }
+    }
+}
+
+/// Input of the local player for the next tick, read from the keyboard and
+/// gamepads of player slot 1.
+#[derive(Resource, Default)]
+pub struct LocalInput(pub FrameInput);
+
+/// Everything sent to the remote peer in one tick. Inputs are sent until
+/// they are acknowledged, so lost packets don't lose inputs.
+#[derive(Debug, Default, PartialEq)]
+struct Packet {
+    /// Last tick up to which the sender has all of the receiver's inputs.
+    ack: u32,
+    /// A confirmed tick of the sender and its checksum.
+    checksum_frame: u32,
+    checksum: u64,
+    /// Tick of the first input.
+    first_frame: u32,
+    inputs: Vec<FrameInput>,
+}
+
+impl Packet {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(21 + self.inputs.len() * 9);
+        bytes.extend(self.ack.to_le_bytes());
+        bytes.extend(self.checksum_frame.to_le_bytes());
+        bytes.extend(self.checksum.to_le_bytes());
+        bytes.extend(self.first_frame.to_le_bytes());
+        bytes.push(self.inputs.len() as u8);
+        for input in &self.inputs {
+            bytes.push(input.bits);
+            bytes.extend(input.stick.x.to_le_bytes());
+            bytes.extend(input.stick.y.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Packet> {
+        let u32_at = |i: usize| Some(u32::from_le_bytes(bytes.get(i..i + 4)?.try_into().ok()?));
+        let f32_at = |i: usize| u32_at(i).map(f32::from_bits);
+        let count = *bytes.get(20)? as usize;
+        if bytes.len() != 21 + count * 9 {
+            return None;
+        }
+        let inputs = (0..count)
+            .map(|n| 21 + n * 9)
+            .map(|i| Some(FrameInput {
+                bits: bytes[i],
+                stick: Vec2::new(f32_at(i + 1)?, f32_at(i + 5)?),
+            }))
+            .collect::<Option<Vec<_>>>()?;
+        Some(Packet {
+            ack: u32_at(0)?,
+            checksum_frame: u32_at(4)?,
+            checksum: u64::from_le_bytes(bytes.get(8..16)?.try_into().ok()?),
+            first_frame: u32_at(16)?,
+            inputs,
+        })
+    }
+}
+
+#[derive(QueryData)]
+#[query_data(mutable)]
+pub struct RollbackState {
+    entity: Entity,
+    transform: &'static mut Transform,
+    velocity: &'static mut Velocity,
+    acceleration: &'static mut Acceleration,
+    on_platform: &'static mut OnPlatform,
+    cooldown: &'static mut Cooldown,
+    mass: &'static mut Mass,
+    score: &'static mut Score,
+    kos: &'static mut Kos,
+    damage: &'static mut Damage,
+    jumps: &'static mut Jumps,
+    action_state: &'static mut ActionState,
+    facing: &'static mut Facing,
+    attack_state: &'static mut AttackState,
+    last_hit_by: &'static mut LastHitBy,
+    stats: &'static mut MatchStats,
+}
+
+/// Gameplay components of one player.
+#[derive(Clone)]
+struct PlayerSnapshot {
+    entity: Entity,
+    transform: Transform,
+    velocity: Velocity,
+    acceleration: Acceleration,
+    on_platform: OnPlatform,
+    cooldown: Cooldown,
+    mass: Mass,
+    score: Score,
+    kos: Kos,
+    damage: Damage,
+    jumps: Jumps,
+    action_state: ActionState,
+    facing: Facing,
+    attack_state: AttackState,
+    last_hit_by: LastHitBy,
+    stats: MatchStats,
+}
+
+/// Gameplay state after a tick.
+#[derive(Clone)]
+struct Snapshot {
+    players: Vec<PlayerSnapshot>,
+    hitboxes: Vec<(Hitbox, Transform)>,
+    progress: MatchProgress,
+    phase: MatchPhase,
+    rng: MatchRng,
+    checksum: StateChecksum,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NetStats {
+    pub rollbacks: u32,
+    pub resimulated_frames: u32,
+    /// Ticks spent waiting for the remote peer.
+    pub stalls: u32,
+    /// Confirmed ticks whose checksums were compared with the remote peer.
+    pub compared_frames: u32,
+    /// Confirmed ticks whose checksums differed from the remote peer.
+    pub desyncs: u32,
+}
+
+/// A two-player match against a remote peer.
+#[derive(Resource)]
+pub struct NetSession {
+    transport: Box<dyn Transport>,
+    pub local_player: u32,
+    pub remote_player: u32,
+    pub input_delay: u32,
+    local_inputs: BTreeMap<u32, FrameInput>,
+    remote_inputs: BTreeMap<u32, FrameInput>,
+    /// Remote inputs ticks were simulated with before they arrived.
+    predicted: BTreeMap<u32, FrameInput>,
+    /// Last tick up to which all remote inputs arrived.
+    confirmed: u32,
+    /// Last tick up to which the remote peer has all local inputs.
+    remote_confirmed: u32,
+    snapshots: BTreeMap<u32, Snapshot>,
+    checksums: BTreeMap<u32, u64>,
+    remote_checksums: BTreeMap<u32, u64>,
+    /// Last tick whose checksum was compared with the remote one.
+    compared: u32,
+    stalled: bool,
+    pub stats: NetStats,
+}
+
+impl NetSession {
+    pub fn new(transport: impl Transport, local_player: u32, input_delay: u32) -> Self {
+        NetSession {
+            transport: Box::new(transport),
+            local_player,
+            remote_player: 3 - local_player,
+            input_delay,
+            local_inputs: BTreeMap::new(),
+            remote_inputs: BTreeMap::new(),
+            predicted: BTreeMap::new(),
+            confirmed: 0,
+            remote_confirmed: 0,
+            snapshots: BTreeMap::new(),
+            checksums: BTreeMap::new(),
+            remote_checksums: BTreeMap::new(),
+            compared: 0,
+            stalled: false,
+            stats: NetStats::default(),
+        }
+    }
+
+    /// Last tick up to which all remote inputs arrived.
+    pub fn confirmed(&self) -> u32 {
+        self.confirmed
+    }
+
+    /// Checksums of recent ticks which won't be rolled back anymore.
+    pub fn confirmed_checksums(&self) -> impl Iterator<Item = (u32, u64)> + '_ {
+        self.checksums.range(..=self.confirmed).map(|(frame, hash)| (*frame, *hash))
+    }
+
+    /// Forgets the previous match, keeping the connection.
+    fn reset(&mut self) {
+        self.local_inputs.clear();
+        self.remote_inputs.clear();
+        self.predicted.clear();
+        self.confirmed = 0;
+        self.remote_confirmed = 0;
+        self.snapshots.clear();
+        self.checksums.clear();
+        self.remote_checksums.clear();
+        self.compared = 0;
+        self.stalled = false;
+        self.stats = NetStats::default();
+    }
+
+    /// Input of `player` in tick `frame`, predicting missing remote inputs.
+    fn input(&mut self, player: u32, frame: u32) -> FrameInput {
+        if player == self.local_player {
+            return self.local_inputs.get(&frame).copied().unwrap_or_default();
+        }
+        if let Some(input) = self.remote_inputs.get(&frame) {
+            return *input;
+        }
+        let prediction = self.remote_inputs.range(..frame).next_back()
+            .map_or(FrameInput::default(), |(_, input)| *input);
+        self.predicted.insert(frame, prediction);
+        prediction
+    }
+
+    /// Reads incoming packets and returns the first simulated tick whose
+    /// remote input was mispredicted.
+    fn receive(&mut self, simulated: u32) -> Option<u32> {
+        let mut mispredicted: Option<u32> = None;
+        for bytes in self.transport.receive() {
+            let Some(packet) = Packet::decode(&bytes) else {
+                warn!("invalid packet from remote peer");
+                continue;
+            };
+            // The remote peer can't run further ahead than it may predict past
+            // our inputs, which are sent `input_delay` ticks early. The margin
+            // covers a remote peer with a longer input delay.
+            let horizon = simulated.saturating_add(2 * (MAX_PREDICTION + self.input_delay));
+            let end = packet.first_frame.checked_add(packet.inputs.len() as u32);
+            if end.is_none_or(|end| end > horizon) {
+                warn!("packet with inputs beyond frame {} from remote peer", horizon);
+                continue;
+            }
+            self.remote_confirmed = self.remote_confirmed.max(packet.ack);
+            if packet.checksum_frame > self.compared {
+                self.remote_checksums.insert(packet.checksum_frame, packet.checksum);
+            }
+            for (i, input) in packet.inputs.into_iter().enumerate() {
+                let Some(frame) = packet.first_frame.checked_add(i as u32) else {
+                    break;
+                };
+                if frame <= self.confirmed || self.remote_inputs.contains_key(&frame) {
+                    continue;
+                }
+                self.remote_inputs.insert(frame, input);
+                if frame <= simulated && self.predicted.get(&frame) != Some(&input) {
+                    mispredicted = Some(mispredicted.map_or(frame, |f| f.min(frame)));
+                }
+            }
+        }
+        while self.remote_inputs.contains_key(&(self.confirmed + 1)) {
+            self.confirmed += 1;
+        }
+        mispredicted
+    }
+
+    fn send(&mut self, simulated: u32) {
+        let unacknowledged = self.local_inputs.range(self.remote_confirmed + 1..);
+        let first_frame = unacknowledged.clone().next().map_or(0, |(frame, _)| *frame);
+        let inputs: Vec<FrameInput> = unacknowledged
+            .map(|(_, input)| *input)
+            .take(MAX_PACKET_INPUTS)
+            .collect();
+        let checksum_frame = self.confirmed.min(simulated);
+        let packet = Packet {
+            ack: self.confirmed,
+            checksum_frame,
+            checksum: self.checksums.get(&checksum_frame).copied().unwrap_or_default(),
+            first_frame,
+            inputs,
+        };
+        self.transport.send(&packet.encode());
+    }
+
+    /// Compares the checksums of ticks confirmed on both peers.
+    fn compare_checksums(&mut self) {
+        let simulated = self.checksums.keys().next_back().copied().unwrap_or(0);
+        let comparable: Vec<(u32, u64)> = self.remote_checksums.range(..=self.confirmed.min(simulated))
+            .map(|(frame, hash)| (*frame, *hash))
+            .collect();
+        for (frame, remote_hash) in comparable {
+            self.remote_checksums.remove(&frame);
+            let Some(hash) = self.checksums.get(&frame) else {
+                continue;
+            };
+            self.compared = frame;
+            self.stats.compared_frames += 1;
+            if *hash != remote_hash {
+                self.stats.desyncs += 1;
+                warn!("desync in frame {}: checksum {:016x}, remote {:016x}",
+                      frame, hash, remote_hash);
+            }
+        }
+    }
+
+    /// Drops what can't be needed anymore.
+    fn prune(&mut self) {
+        let confirmed = self.confirmed;
+        // Local inputs are still needed to resimulate unconfirmed ticks.
+        let acknowledged = self.remote_confirmed.min(confirmed);
+        self.local_inputs.retain(|frame, _| *frame > acknowledged);
+        self.remote_inputs.retain(|frame, _| *frame >= confirmed);
+        self.predicted.retain(|frame, _| *frame > confirmed);
+        self.snapshots.retain(|frame, _| *frame >= confirmed);
+        self.checksums.retain(|frame, _| *frame + CHECKSUM_HISTORY > confirmed);
+    }
+}
+
+/// Sets up a two-player match against the remote peer of `session` and
+/// starts it. Both peers need the same seed, rules, stage and characters.
+pub fn start_session(world: &mut World, session: NetSession) {
+    world.insert_resource(crate::game_match::PlayerCount(2));
+    world.insert_resource(session);
+    world.resource_mut::<NextState<GameStates>>().set(GameStates::Game);
+}
+
+fn take_snapshot(players: &Query<RollbackStateReadOnly>,
+                 hitboxes: &Query<(&Hitbox, &Transform)>,
+                 progress: &MatchProgress,
+                 phase: MatchPhase,
+                 rng: &MatchRng,
+                 checksum: &StateChecksum) -> Snapshot {
+    Snapshot {
+        players: players.iter()
+            .map(|state| PlayerSnapshot {
+                entity: state.entity,
+                transform: *state.transform,
+                velocity: state.velocity.clone(),
+                acceleration: state.acceleration.clone(),
+                on_platform: state.on_platform.clone(),
+                cooldown: state.cooldown.clone(),
+                mass: state.mass.clone(),
+                score: state.score.clone(),
+                kos: state.kos.clone(),
+                damage: state.damage.clone(),
+                jumps: state.jumps.clone(),
+                action_state: *state.action_state,
+                facing: state.facing.clone(),
+                attack_state: state.attack_state.clone(),
+                last_hit_by: state.last_hit_by.clone(),
+                stats: state.stats.clone(),
+            })
+            .collect(),
+        hitboxes: hitboxes.iter().map(|(hitbox, tf)| (hitbox.clone(), *tf)).collect(),
+        progress: progress.clone(),
+        phase,
+        rng: rng.clone(),
+        checksum: *checksum,
+    }
+}
+
+fn save_snapshot(players: Query<RollbackStateReadOnly>,
+                 hitboxes: Query<(&Hitbox, &Transform)>,
+                 (progress, phase, next_phase): (Res<MatchProgress>, Res<State<MatchPhase>>, Res<NextState<MatchPhase>>),
+                 rng: Res<MatchRng>,
+                 checksum: Res<StateChecksum>,
+                 mut session: ResMut<NetSession>) {
+    let phase = current_phase(&phase, &next_phase);
+    let snapshot = take_snapshot(&players, &hitboxes, &progress, phase, &rng, &checksum);
+    session.checksums.insert(checksum.frame, checksum.hash);
+    session.snapshots.insert(checksum.frame, snapshot);
+}
+
+fn restore_snapshot(world: &mut World, snapshot: Snapshot) {
+    // Players who are out are only disabled, the snapshot has the ones in play.
+    let all_players: Vec<(Entity, bool)> = world
+        .query_filtered::<(Entity, Has<Disabled>), (With<Player>, Allow<Disabled>)>()
+        .iter(world)
+        .collect();
+    for (entity, disabled) in all_players {
+        let in_play = snapshot.players.iter().any(|p| p.entity == entity);
+        if in_play && disabled {
+            world.entity_mut(entity).remove::<Disabled>();
+        }
+        else if !in_play && !disabled {
+            world.entity_mut(entity).insert(Disabled);
+        }
+    }
+    let mut players = world.query::<RollbackState>();
+    for mut state in players.iter_mut(world) {
+        let Some(saved) = snapshot.players.iter().find(|p| p.entity == state.entity) else {
+            continue;
+        };
+        let saved = saved.clone();
+        *state.transform = saved.transform;
+        *state.velocity = saved.velocity;
+        *state.acceleration = saved.acceleration;
+        *state.on_platform = saved.on_platform;
+        *state.cooldown = saved.cooldown;
+        *state.mass = saved.mass;
+        *state.score = saved.score;
+        *state.kos = saved.kos;
+        *state.damage = saved.damage;
+        *state.jumps = saved.jumps;
+        *state.action_state = saved.action_state;
+        *state.facing = saved.facing;
+        *state.attack_state = saved.attack_state;
+        *state.last_hit_by = saved.last_hit_by;
+        *state.stats = saved.stats;
+    }
+    let hitboxes: Vec<Entity> = world.query_filtered::<Entity, With<Hitbox>>().iter(world).collect();
+    for entity in hitboxes {
+        world.despawn(entity);
+    }
+    for (hitbox, tf) in snapshot.hitboxes {
+        world.spawn((
+            DespawnOnExit(GameStates::Game),
+            hitbox,
+            tf,
+            Sprite::from_color(HITBOX_COLOR, Vec2::ONE),
+        ));
+    }
+    world.insert_resource(snapshot.progress);
+    // There are no systems on entering a phase, so it's set without a transition.
+    world.insert_resource(State::new(snapshot.phase));
+    world.insert_resource(NextState::<MatchPhase>::Unchanged);
+    world.insert_resource(snapshot.rng);
+    world.insert_resource(snapshot.checksum);
+}
+
+pub fn read_net_input(keyboard_input: Res<ButtonInput<KeyCode>>,
+                  input_map: Res<InputMap>,
+                  gamepads: Query<&Gamepad>,
+                  mut local_input: ResMut<LocalInput>) {
+    let mut action_state = ActionState::default();
+    read_slot(1, &input_map, &keyboard_input, &gamepads, &mut action_state);
+    local_input.0 = FrameInput::from(&action_state);
+}
+
+/// Exchanges inputs with the remote peer, rolls back and resimulates
+/// mispredicted ticks, and decides whether the next tick can be simulated.
+pub fn advance_session(world: &mut World) {
+    if matches!(world.resource::<NextState<GameStates>>(), NextState::Pending(_)) {
+        return;
+    }
+    let simulated = world.resource::<StateChecksum>().frame;
+    if simulated == 0 && world.resource::<NetSession>().snapshots.is_empty() {
+        world.run_system_cached(save_snapshot).unwrap();
+    }
+
+    let mispredicted = world.resource_mut::<NetSession>().receive(simulated);
+    if let Some(frame) = mispredicted {
+        let mut session = world.resource_mut::<NetSession>();
+        session.stalled = false;
+        session.stats.rollbacks += 1;
+        session.stats.resimulated_frames += simulated + 1 - frame;
+        let snapshot = session.snapshots.get(&(frame - 1)).cloned()
+            .expect("snapshots are kept back to the last confirmed tick");
+        restore_snapshot(world, snapshot);
+        for _ in frame..=simulated {
+            world.run_schedule(FixedUpdate);
+        }
+    }
+    // Resimulation stops early if the match is decided sooner than before.
+    let simulated = world.resource::<StateChecksum>().frame;
+    let decided = world.resource::<MatchProgress>().results.is_some();
+    let mut session = world.resource_mut::<NetSession>();
+    session.snapshots.retain(|frame, _| *frame <= simulated);
+    session.checksums.retain(|frame, _| *frame <= simulated);
+    if decided && session.confirmed >= simulated {
+        world.run_system_cached(end_match).unwrap();
+    }
+
+    let local_input = world.resource::<LocalInput>().0;
+    let mut session = world.resource_mut::<NetSession>();
+    // A decided match waits until its result is confirmed, or undone by a rollback.
+    session.stalled = decided || simulated >= session.confirmed + MAX_PREDICTION;
+    if session.stalled {
+        session.stats.stalls += 1;
+    }
+    else {
+        // The first ticks before the delayed inputs start are played without input.
+        let delay = session.input_delay;
+        if simulated == 0 {
+            session.local_inputs.extend((1..=delay).map(|frame| (frame, FrameInput::default())));
+        }
+        session.local_inputs.insert(simulated + 1 + delay, local_input);
+    }
+    session.send(simulated);
+    session.compare_checksums();
+    session.prune();
+}
+
+/// Applies the local and the confirmed or predicted remote input of the
+/// upcoming tick.
+fn apply_net_inputs(mut session: ResMut<NetSession>,
+                    checksum: Res<StateChecksum>,
+                    mut query: Query<(&mut ActionState, &Player)>) {
+    let frame = checksum.frame + 1;
+    for (mut action_state, player) in &mut query {
+        let input = session.input(player.0, frame);
+        action_state.advance();
+        action_state.set_bits(input.bits);
+        action_state.set_stick(input.stick);
+    }
+}
+
+fn reset_session(mut session: ResMut<NetSession>) {
+    session.reset();
+}
+
+/// Whether the session isn't waiting for the remote peer, or for the
+/// result of a decided match to be confirmed.
+fn can_advance(session: Option<Res<NetSession>>, progress: Res<MatchProgress>) -> bool {
+    session.is_none_or(|session| !session.stalled && progress.results.is_none())
+}
+
+/// Runs a [`NetSession`] if there is one.
+pub struct NetcodePlugin;
+impl Plugin for NetcodePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LocalInput>();
+        app.add_systems(OnEnter(GameStates::Game), reset_session.run_if(resource_exists::<NetSession>));
+        app.add_systems(FixedPreUpdate, (
+            read_net_input.run_if(resource_exists::<InputMap>),
+            advance_session,
+        ).chain().run_if(resource_exists::<NetSession>).run_if(in_state(GameStates::Game)));
+        app.add_systems(FixedUpdate, (
+            apply_net_inputs.in_set(GameSystems::Input),
+            save_snapshot.after(compute_checksum).in_set(GameSystems::Checksum),
+        ).run_if(resource_exists::<NetSession>).in_set(GameSet));
+        app.configure_sets(FixedUpdate, GameSet.run_if(can_advance));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out the given packets on the first receive.
+    struct Inbox(Vec<Vec<u8>>);
+
+    impl Transport for Inbox {
+        fn send(&mut self, _packet: &[u8]) {}
+
+        fn receive(&mut self) -> Vec<Vec<u8>> {
+            std::mem::take(&mut self.0)
+        }
+    }
+
+    fn receive(packets: &[Packet], simulated: u32) -> NetSession {
+        let inbox = Inbox(packets.iter().map(Packet::encode).collect());
+        let mut session = NetSession::new(inbox, 1, DEFAULT_INPUT_DELAY);
+        session.receive(simulated);
+        session
+    }
+
+    fn inputs(first_frame: u32, count: usize) -> Packet {
+        Packet { first_frame, inputs: vec![FrameInput { bits: 1, stick: Vec2::X }; count], ..default() }
+    }
+
+    #[test]
+    fn packet_round_trip() {
+        let packet = Packet { ack: 3, checksum_frame: 2, checksum: 0xdead_beef, ..inputs(4, 3) };
+        assert_eq!(Packet::decode(&packet.encode()), Some(packet));
+    }
+
+    #[test]
+    fn inputs_are_confirmed_in_order() {
+        let session = receive(&[inputs(1, 5), inputs(8, 2)], 0);
+        assert_eq!(session.confirmed(), 5);
+        assert_eq!(session.remote_inputs.len(), 7);
+    }
+
+    #[test]
+    fn frames_past_u32_max_are_rejected() {
+        let session = receive(&[inputs(u32::MAX - 1, 4), inputs(u32::MAX, 1)], u32::MAX - 1);
+        assert!(session.remote_inputs.is_empty());
+    }
+
+    #[test]
+    fn far_future_inputs_are_rejected() {
+        let session = receive(&[inputs(1_000_000, 2), Packet { ack: 7, ..inputs(30, 10) }], 10);
+        assert!(session.remote_inputs.is_empty());
+        // Nothing of a rejected packet is used.
+        assert_eq!(session.remote_confirmed, 0);
+    }
+}
@@ -0,0 +1,287 @@
+//! The "Online" menu: browsing, hosting and joining lobbies on a
+//! `platform-fighter-server`, picking characters and the stage there, and
+//! starting a [`NetSession`] once both players are ready.
+
+use bevy::prelude::*;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+
+use crate::character::{CharacterPick, CharacterRegistry, CharacterSelection, Fighter};
+use crate::determinism::MatchSeed;
+use crate::game_match::MatchRules;
+use crate::input::{read_slot_input, Action, SlotActions};
+use crate::lobby::{
+    decode, encode, ClientMessage, LobbyState, LobbySummary, LobbyTransport, ServerMessage,
+    DEFAULT_SERVER_ADDR, HEARTBEAT_SECS,
+};
+use crate::netcode::{start_session, NetSession, DEFAULT_INPUT_DELAY};
+use crate::stage::{ActiveStage, StageList};
+use crate::GameStates;
+
+/// Lobby server to connect to from the online menu.
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct OnlineSettings {
+    pub server: SocketAddr,
+}
+
+impl Default for OnlineSettings {
+    fn default() -> Self {
+        OnlineSettings { server: DEFAULT_SERVER_ADDR.parse().unwrap() }
+    }
+}
+
+/// Connection to the lobby server while in the online menu.
+#[derive(Resource)]
+pub struct LobbyClient {
+    /// Handed over to the [`NetSession`] when the match starts.
+    socket: Option<UdpSocket>,
+    pub server: SocketAddr,
+    pub lobbies: Vec<LobbySummary>,
+    /// Index into `lobbies` of the lobby to join.
+    pub selected: usize,
+    /// The lobby joined, and this client's player number in it.
+    pub lobby: Option<(LobbyState, u32)>,
+    pub error: Option<String>,
+    since_heartbeat: f32,
+}
+
+impl LobbyClient {
+    fn connect(server: SocketAddr) -> std::io::Result<LobbyClient> {
+        let local: SocketAddr = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+        let socket = UdpSocket::bind(local)?;
+        socket.set_nonblocking(true)?;
+        Ok(LobbyClient {
+            socket: Some(socket),
+            server,
+            lobbies: Vec::new(),
+            selected: 0,
+            lobby: None,
+            error: None,
+            since_heartbeat: HEARTBEAT_SECS,
+        })
+    }
+
+    fn send(&mut self, message: &ClientMessage) {
+        let Some(socket) = &self.socket else {
+            return;
+        };
+        if let Err(e) = socket.send_to(&encode(message), self.server) {
+            self.error = Some(format!("could not reach {}: {}", self.server, e));
+        }
+    }
+
+    fn receive(&mut self) -> Vec<ServerMessage> {
+        let mut messages = Vec::new();
+        let mut buffer = [0; 4096];
+        while let Some(socket) = &self.socket {
+            match socket.recv_from(&mut buffer) {
+                Ok((len, from)) if from == self.server => {
+                    messages.extend(decode::<ServerMessage>(&buffer[..len]));
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => { break; }
+                Err(e) => {
+                    // E.g. nothing listening on the server's port.
+                    self.error = Some(format!("could not reach {}: {}", self.server, e));
+                    break;
+                }
+            }
+        }
+        messages
+    }
+
+    /// The own member of the joined lobby.
+    fn pick(&self) -> Option<CharacterPick> {
+        let (lobby, player) = self.lobby.as_ref()?;
+        let member = lobby.members.get(*player as usize - 1)?;
+        Some(CharacterPick { fighter: Fighter(member.fighter), palette: member.palette, ready: member.ready })
+    }
+}
+
+fn connect_lobby(mut commands: Commands,
+                 mut next_state: ResMut<NextState<GameStates>>,
+                 settings: Res<OnlineSettings>) {
+    match LobbyClient::connect(settings.server) {
+        Ok(client) => { commands.insert_resource(client); }
+        Err(e) => {
+            error!("could not open a socket: {}", e);
+            next_state.set(GameStates::Menu);
+        }
+    }
+}
+
+fn disconnect_lobby(mut commands: Commands, client: Option<ResMut<LobbyClient>>) {
+    if let Some(mut client) = client
+        && client.lobby.is_some() {
+        client.send(&ClientMessage::Leave);
+    }
+    commands.remove_resource::<LobbyClient>();
+}
+
+fn heartbeat(mut client: ResMut<LobbyClient>, time: Res<Time<Real>>) {
+    client.since_heartbeat += time.delta_secs();
+    if client.since_heartbeat < HEARTBEAT_SECS {
+        return;
+    }
+    client.since_heartbeat = 0.;
+    // Outside of a lobby the heartbeat also refreshes the list of lobbies.
+    let message = if client.lobby.is_some() { ClientMessage::Heartbeat } else { ClientMessage::ListLobbies };
+    client.send(&message);
+}
+
+fn receive_lobby_messages(mut commands: Commands,
+                          mut client: ResMut<LobbyClient>,
+                          stage_list: Res<StageList>) {
+    for message in client.receive() {
+        match message {
+            ServerMessage::Lobbies { lobbies } => {
+                client.selected = client.selected.min(lobbies.len().saturating_sub(1));
+                client.lobbies = lobbies;
+            }
+            ServerMessage::Lobby { lobby, player } => {
+                client.lobby = Some((lobby, player));
+                client.error = None;
+            }
+            ServerMessage::Error { message } => { client.error = Some(message); }
+            ServerMessage::Start { seed, player, peer } => {
+                let Some((lobby, _)) = client.lobby.clone() else {
+                    continue;
+                };
+                let Some(stage) = stage_list.find(&lobby.stage) else {
+                    client.error = Some(format!("unknown stage {}", lobby.stage));
+                    continue;
+                };
+                client.send(&ClientMessage::Started);
+                let Some(socket) = client.socket.take() else {
+                    continue;
+                };
+                info!("online match as player {} against {}", player, peer);
+                let picks = lobby.members.iter().enumerate()
+                    .map(|(i, member)| (i as u32 + 1, CharacterPick {
+                        fighter: Fighter(member.fighter),
+                        palette: member.palette,
+                        ready: true,
+                    }))
+                    .collect();
+                commands.insert_resource(MatchSeed(seed));
+                commands.insert_resource(MatchRules { win_condition: lobby.win_condition });
+                commands.insert_resource(ActiveStage(stage.clone()));
+                commands.insert_resource(CharacterSelection { picks });
+                let transport = LobbyTransport::new(socket, client.server, peer);
+                let session = NetSession::new(transport, player, DEFAULT_INPUT_DELAY);
+                commands.queue(move |world: &mut World| start_session(world, session));
+                return;
+            }
+        }
+    }
+}
+
+/// Up and down pick a lobby, enter joins it, H hosts a new one with the
+/// rules picked in the menu.
+fn browse_lobbies(mut client: ResMut<LobbyClient>,
+                  mut next_state: ResMut<NextState<GameStates>>,
+                  rules: Res<MatchRules>,
+                  active_stage: Res<ActiveStage>,
+                  slot_actions: Res<SlotActions>,
+                  keyboard_input: Res<ButtonInput<KeyCode>>) {
+    if client.lobby.is_some() {
+        return;
+    }
+    if keyboard_input.just_pressed(KeyCode::Escape) {
+        next_state.set(GameStates::Menu);
+        return;
+    }
+    if keyboard_input.just_pressed(KeyCode::KeyH) {
+        client.send(&ClientMessage::Host {
+            win_condition: rules.win_condition,
+            stage: active_stage.0.name.clone(),
+        });
+        return;
+    }
+    let Some(actions) = slot_actions.0.get(&1) else {
+        return;
+    };
+    let count = client.lobbies.len();
+    if count > 0 {
+        if actions.just_pressed(Action::Down) {
+            client.selected = (client.selected + 1) % count;
+        }
+        if actions.just_pressed(Action::Up) {
+            client.selected = (client.selected + count - 1) % count;
+        }
+    }
+    if keyboard_input.just_pressed(KeyCode::Enter)
+        && let Some(lobby) = client.lobbies.get(client.selected) {
+        let lobby = lobby.id;
+        client.send(&ClientMessage::Join { lobby });
+    }
+}
+
+/// Like the character select: left and right for the character, up and
+/// down for the palette and attack to get ready. The host picks the stage
+/// with S.
+fn edit_lobby(mut client: ResMut<LobbyClient>,
+              registry: Res<CharacterRegistry>,
+              stage_list: Res<StageList>,
+              slot_actions: Res<SlotActions>,
+              keyboard_input: Res<ButtonInput<KeyCode>>) {
+    let (Some((lobby, player)), Some(mut pick)) = (client.lobby.clone(), client.pick()) else {
+        return;
+    };
+    if keyboard_input.just_pressed(KeyCode::Escape) {
+        client.send(&ClientMessage::Leave);
+        client.lobby = None;
+        return;
+    }
+    if player == 1 && keyboard_input.just_pressed(KeyCode::KeyS) {
+        let stages = &stage_list.stages;
+        let index = stages.iter().position(|stage| stage.name == lobby.stage).map_or(0, |i| i + 1);
+        client.send(&ClientMessage::Stage { stage: stages[index % stages.len()].name.clone() });
+    }
+    let Some(actions) = slot_actions.0.get(&1) else {
+        return;
+    };
+    let characters = registry.characters.len();
+    let palettes = registry.get(pick.fighter).palettes.len().max(1);
+    let before = pick;
+    if actions.just_pressed(Action::Attack) {
+        pick.ready = !pick.ready;
+    }
+    else if !pick.ready {
+        if actions.just_pressed(Action::Right) {
+            pick.fighter = Fighter((pick.fighter.0 + 1) % characters);
+            pick.palette = 0;
+        }
+        if actions.just_pressed(Action::Left) {
+            pick.fighter = Fighter((pick.fighter.0 + characters - 1) % characters);
+            pick.palette = 0;
+        }
+        if actions.just_pressed(Action::Down) {
+            pick.palette = (pick.palette + 1) % palettes;
+        }
+        if actions.just_pressed(Action::Up) {
+            pick.palette = (pick.palette + palettes - 1) % palettes;
+        }
+    }
+    if pick != before {
+        client.send(&ClientMessage::Pick {
+            fighter: pick.fighter.0,
+            palette: pick.palette,
+            ready: pick.ready,
+        });
+    }
+}
+
+pub struct OnlinePlugin;
+impl Plugin for OnlinePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<OnlineSettings>();
+        app.add_systems(OnEnter(GameStates::Lobby), connect_lobby);
+        app.add_systems(OnExit(GameStates::Lobby), disconnect_lobby);
+        app.add_systems(Update, (
+            heartbeat,
+            receive_lobby_messages,
+            (browse_lobbies, edit_lobby).after(read_slot_input),
+        ).chain().run_if(resource_exists::<LobbyClient>).run_if(in_state(GameStates::Lobby)));
+    }
+}
@@ -0,0 +1,229 @@
+use bevy::ecs::schedule::ScheduleLabel;
+use bevy::prelude::*;
+
+use crate::{GameSet, GameSystems, NULL_VECTOR};
+
+// All quantities are in units per second (or per second squared) and are
+// integrated with the `Time<Fixed>` delta, so the tick rate can be changed
+// by inserting `Time::<Fixed>::from_hz` without changing the game.
+pub const DEFAULT_TICK_RATE: f64 = 64.;
+/// Enough for a fighter at the special move's speed to move less than its
+/// own height per substep.
+pub const DEFAULT_SUBSTEPS: u32 = 4;
+pub const PLAYER_MOVEMENT_FORCE: Vec3 = Vec3::new(8192.0, 0.0, 0.0);
+pub const PLAYER_MOVEMENT_FORCE_AIR: Vec3 = Vec3::new(6144.0, 0.0, 0.0);
+pub const GRAVTITON_FORCE: Vec3 = Vec3::new(0., -4096., 0.);
+/// Friction in 1/unit, multiplied with the squared speed.
+pub const FRICTION_QUADRATIC: f32 = 0.005;
+/// Friction in 1/s, multiplied with the speed.
+pub const FRICTION_LINEAR: f32 = 3.2;
+/// Fastest anything moves, in units per second. At the default tick rate
+/// and substeps that's about 31 units per substep, less than the 50 units
+/// platforms are thick.
+pub const TERMINAL_VELOCITY: f32 = 8000.;
+
+#[derive(Component, Clone)]
+pub struct Velocity(pub Vec3);
+
+/// Number of times per tick velocities are applied and collisions resolved,
+/// each time for a fraction of the tick. More substeps keep fast fighters
+/// from being pushed through platforms by other fighters.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Substeps(pub u32);
+
+impl Default for Substeps {
+    fn default() -> Self {
+        Substeps(DEFAULT_SUBSTEPS)
+    }
+}
+
+impl Substeps {
+    /// Seconds simulated by one substep of a tick of `time`.
+    pub fn secs(&self, time: &Time) -> f32 {
+        time.delta_secs() / self.0.max(1) as f32
+    }
+}
+
+/// Runs [`Substeps`] times per tick, with the movement and collision systems
+/// of [`GameSystems::Integrate`] and [`GameSystems::Collide`].
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PhysicsSubstep;
+
+#[derive(Component)]
+pub struct MovementForce {
+    pub ground: Vec3,
+    pub air: Vec3,
+}
+
+#[derive(Component, Clone)]
+pub struct Acceleration(pub Vec3);
+
+/// Slows the entity down, applied with the velocity in every substep as it
+/// depends on the velocity, which collisions change during the tick.
+#[derive(Component)]
+pub struct FrictionForce;
+
+#[derive(Component)]
+pub struct GravitationForce(pub Vec3);
+
+#[derive(Component, Clone)]
+pub struct Mass(pub f32);
+
+fn friction(v: Vec3) -> Vec3 {
+    -(FRICTION_QUADRATIC * v.length_squared() + FRICTION_LINEAR * v.length()) * v.normalize_or(NULL_VECTOR)
+}
+
+fn gravitation_force(mut query: Query<(&mut Acceleration, &GravitationForce)>) {
+    for (mut accel, g) in &mut query {
+        accel.0 += g.0;
+    }
+}
+
+pub fn apply_velocity(time: Res<Time>,
+                      substeps: Res<Substeps>,
+                      mut query: Query<(&mut Transform, &mut Velocity, &Acceleration, Has<FrictionForce>)>) {
+    let dt = substeps.secs(&time);
+    for (mut transform, mut velocity, accel, has_friction) in &mut query {
+        // Hits can launch faster than that, it's capped before the friction
+        // so that can't overshoot.
+        let v = velocity.0.clamp_length_max(TERMINAL_VELOCITY);
+        let friction = if has_friction { friction(v) } else { NULL_VECTOR };
+        velocity.0 = (v + (accel.0 + friction) * dt).clamp_length_max(TERMINAL_VELOCITY);
+        transform.translation.x += velocity.0.x * dt;
+        transform.translation.y += velocity.0.y * dt;
+    }
+}
+
+fn run_substeps(world: &mut World) {
+    for _ in 0..world.resource::<Substeps>().0.max(1) {
+        world.run_schedule(PhysicsSubstep);
+    }
+}
+
+/// Forces are added up anew every tick.
+fn clear_acceleration(mut query: Query<&mut Acceleration>) {
+    for mut accel in &mut query {
+        accel.0 = NULL_VECTOR;
+    }
+}
+
+pub struct PhysicsPlugin;
+impl Plugin for PhysicsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(FixedUpdate, (
+            gravitation_force.in_set(GameSystems::Forces),
+            (run_substeps, clear_acceleration).chain().in_set(GameSystems::Integrate),
+        ).in_set(GameSet));
+        app.init_resource::<Substeps>();
+        app.init_schedule(PhysicsSubstep);
+        app.configure_sets(PhysicsSubstep, (GameSystems::Integrate, GameSystems::Collide).chain());
+        app.add_systems(PhysicsSubstep, apply_velocity.in_set(GameSystems::Integrate));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collision::OnPlatform;
+    use crate::input::Action;
+    use crate::sim::{headless_app, InputScript, ScriptEvent, SimFrame, SimulationConfig};
+    use crate::Player;
+
+    const JUMP_SECS: f64 = 0.5;
+    /// The integration error of a tick is proportional to its length, this
+    /// is about 5% of the height of a jump.
+    const TOLERANCE: f32 = 10.;
+
+    /// Height of player 1 above where they jumped from, by seconds since
+    /// the jump, for a jump at `tick_rate`.
+    fn jump_heights(tick_rate: f64) -> Vec<(f32, f32)> {
+        let jump_frame = (JUMP_SECS * tick_rate) as u32;
+        let config = SimulationConfig {
+            tick_rate,
+            script: InputScript {
+                events: vec![ScriptEvent { frame: jump_frame, player: 1, press: vec![Action::Jump], release: vec![] }],
+            },
+            ..default()
+        };
+        let mut app = headless_app(&config);
+        let mut start = None;
+        let mut heights = Vec::new();
+        for _ in 0..(2. * tick_rate) as u32 {
+            app.update();
+            let world = app.world_mut();
+            let frame = world.resource::<SimFrame>().0;
+            let y = world.query::<(&Transform, &Player)>()
+                .iter(world)
+                .find(|(_, player)| player.0 == 1)
+                .map(|(tf, _)| tf.translation.y)
+                .unwrap();
+            if frame == jump_frame {
+                start = Some(y);
+            }
+            if let Some(start) = start && frame > jump_frame {
+                heights.push(((frame - jump_frame) as f32 / tick_rate as f32, y - start));
+            }
+        }
+        heights
+    }
+
+    #[test]
+    fn jump_is_independent_of_tick_rate() {
+        let slow = jump_heights(60.);
+        let fast = jump_heights(120.);
+        let apex = slow.iter().map(|(_, height)| *height).fold(0., f32::max);
+        assert!(apex > 100., "no jump, apex at {}", apex);
+        for (secs, height) in &slow {
+            let (_, fast_height) = fast.iter().find(|(s, _)| (s - secs).abs() < 1e-4).unwrap();
+            assert!((height - fast_height).abs() < TOLERANCE,
+                    "{} at 60 Hz and {} at 120 Hz after {}s", height, fast_height, secs);
+        }
+    }
+
+    /// Where players standing on the main platform of the default stage are.
+    const GROUND_Y: f32 = -100.;
+
+    /// A match on the default stage with player 1 put at `position`, moving
+    /// at `velocity`.
+    fn launch(position: Vec2, velocity: Vec2) -> (App, Entity) {
+        let mut app = headless_app(&SimulationConfig::default());
+        app.update();
+        let world = app.world_mut();
+        let (entity, mut tf, mut v, _) = world.query::<(Entity, &mut Transform, &mut Velocity, &Player)>()
+            .iter_mut(world)
+            .find(|(.., player)| player.0 == 1)
+            .unwrap();
+        tf.translation = position.extend(0.);
+        v.0 = velocity.extend(0.);
+        (app, entity)
+    }
+
+    #[test]
+    fn speed_is_capped_at_terminal_velocity() {
+        let (mut app, player) = launch(Vec2::new(0., 300.), Vec2::new(3., 4.) * TERMINAL_VELOCITY);
+        app.update();
+        let speed = app.world().get::<Velocity>(player).unwrap().0.length();
+        assert!(speed <= TERMINAL_VELOCITY + 1e-3, "{}", speed);
+    }
+
+    #[test]
+    fn terminal_velocity_does_not_tunnel() {
+        let travel = TERMINAL_VELOCITY / DEFAULT_TICK_RATE as f32;
+        // From every height within a tick of falling, so the platform is
+        // reached in each of the substeps, and faster than allowed.
+        for speed in [TERMINAL_VELOCITY, 4. * TERMINAL_VELOCITY] {
+            for gap in [1., 20., 40., 60., 80., 100., travel - 1.] {
+                let (mut app, player) = launch(Vec2::new(100., GROUND_Y + gap), Vec2::new(0., -speed));
+                for _ in 0..4 {
+                    app.update();
+                    let y = app.world().get::<Transform>(player).unwrap().translation.y;
+                    assert!(y > GROUND_Y - 1e-3, "at {} from {} above at speed {}", y, gap, speed);
+                }
+                let player = app.world().entity(player);
+                let y = player.get::<Transform>().unwrap().translation.y;
+                assert!((y - GROUND_Y).abs() < 1e-3, "at {} from {} above at speed {}", y, gap, speed);
+                assert!(player.get::<OnPlatform>().unwrap().grounded);
+            }
+        }
+    }
+}
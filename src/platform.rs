@@ -0,0 +1,154 @@
+//! Platforms which travel along paths or appear and disappear on timers.
+//!
+//! Where a platform is and whether it's there is a function of the time
+//! played in the match, so rollbacks and replays, which restore the
+//! [`MatchProgress`], put the platforms back in place too.
+
+use bevy::prelude::*;
+use serde::Deserialize;
+
+use crate::game_match::MatchProgress;
+use crate::physics::{PhysicsSubstep, Substeps};
+use crate::{GameSet, GameSystems};
+
+/// What a platform does after reaching the last waypoint of its path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PathMode {
+    /// Stays there.
+    Linear,
+    /// Heads straight back to its starting point and starts over.
+    Loop,
+    /// Travels the path backwards, and forwards again.
+    PingPong,
+}
+
+/// Path of a platform, starting at the platform's position.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PlatformPath {
+    pub waypoints: Vec<Vec2>,
+    /// Units per second.
+    pub speed: f32,
+    pub mode: PathMode,
+}
+
+impl PlatformPath {
+    /// Where a platform starting at `start` is after `secs` seconds.
+    pub fn position(&self, start: Vec2, secs: f32) -> Vec2 {
+        let mut points = vec![start];
+        points.extend(&self.waypoints);
+        if self.mode == PathMode::Loop {
+            points.push(start);
+        }
+        let length: f32 = points.windows(2).map(|w| w[0].distance(w[1])).sum();
+        if length <= 0. {
+            return start;
+        }
+        let travelled = self.speed * secs.max(0.);
+        let mut distance = match self.mode {
+            PathMode::Linear => travelled.min(length),
+            PathMode::Loop => travelled.rem_euclid(length),
+            PathMode::PingPong => length - (travelled.rem_euclid(2. * length) - length).abs(),
+        };
+        for segment in points.windows(2) {
+            let segment_length = segment[0].distance(segment[1]);
+            if distance <= segment_length {
+                return segment[0].move_towards(segment[1], distance);
+            }
+            distance -= segment_length;
+        }
+        points[points.len() - 1]
+    }
+}
+
+/// A platform which is there for `on_secs`, then gone for `off_secs`, and
+/// so on.
+#[derive(Component, Debug, Clone, Copy, Deserialize)]
+pub struct PlatformTimer {
+    pub on_secs: f32,
+    pub off_secs: f32,
+    /// Seconds into the cycle at the start of the match.
+    #[serde(default)]
+    pub offset_secs: f32,
+}
+
+impl PlatformTimer {
+    pub fn is_there(&self, secs: f32) -> bool {
+        let cycle = self.on_secs + self.off_secs;
+        cycle <= 0. || (secs + self.offset_secs).rem_euclid(cycle) < self.on_secs
+    }
+}
+
+/// A platform travelling along `path`.
+#[derive(Component, Debug, Clone)]
+pub struct MovingPlatform {
+    pub start: Vec2,
+    pub path: PlatformPath,
+    /// Velocity during the current tick, which carries the players standing
+    /// on the platform along.
+    pub velocity: Vec2,
+}
+
+impl MovingPlatform {
+    pub fn new(start: Vec2, path: PlatformPath) -> Self {
+        MovingPlatform { start, path, velocity: Vec2::ZERO }
+    }
+}
+
+/// A platform which is gone for now, see [`PlatformTimer`].
+#[derive(Component)]
+pub struct Vanished;
+
+/// Puts the platforms where they are at the start of the tick, heading to
+/// where they are at its end.
+fn steer_platforms(time: Res<Time>,
+                   progress: Res<MatchProgress>,
+                   mut query: Query<(&mut Transform, &mut MovingPlatform)>) {
+    let dt = time.delta_secs();
+    for (mut tf, mut platform) in &mut query {
+        let position = platform.path.position(platform.start, progress.elapsed);
+        let next = platform.path.position(platform.start, progress.elapsed + dt);
+        tf.translation = position.extend(tf.translation.z);
+        platform.velocity = if dt > 0. { (next - position) / dt } else { Vec2::ZERO };
+    }
+}
+
+fn toggle_platforms(mut commands: Commands,
+                    progress: Res<MatchProgress>,
+                    mut query: Query<(Entity, &PlatformTimer, Has<Vanished>, Option<&mut Visibility>)>) {
+    for (entity, timer, vanished, visibility) in &mut query {
+        let there = timer.is_there(progress.elapsed);
+        if there != vanished {
+            continue;
+        }
+        if there {
+            commands.entity(entity).remove::<Vanished>();
+        }
+        else {
+            commands.entity(entity).insert(Vanished);
+        }
+        if let Some(mut visibility) = visibility {
+            *visibility = if there { Visibility::Inherited } else { Visibility::Hidden };
+        }
+    }
+}
+
+fn move_platforms(time: Res<Time>,
+                  substeps: Res<Substeps>,
+                  mut query: Query<(&mut Transform, &MovingPlatform)>) {
+    let dt = substeps.secs(&time);
+    for (mut tf, platform) in &mut query {
+        tf.translation += (platform.velocity * dt).extend(0.);
+    }
+}
+
+pub struct PlatformPlugin;
+impl Plugin for PlatformPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(FixedUpdate, (steer_platforms, toggle_platforms)
+                        .after(GameSystems::Actions)
+                        .before(GameSystems::Integrate)
+                        .in_set(GameSet));
+        app.add_systems(PhysicsSubstep, move_platforms.in_set(GameSystems::Integrate));
+    }
+}
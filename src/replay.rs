@@ -0,0 +1,342 @@
+//! Every match is recorded as a [`Replay`]: the seed, rules, stage and
+//! characters, plus each player's inputs whenever they change. Since matches
+//! are deterministic, feeding the inputs back in reproduces the match.
+
+use bevy::platform::collections::HashMap;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::character::{CharacterPick, CharacterSelection, Fighter};
+use crate::determinism::{compute_checksum, MatchSeed, StateChecksum};
+use crate::game_match::{MatchRules, PlayerCount, WinCondition};
+use crate::physics::{Substeps, DEFAULT_SUBSTEPS};
+use crate::stage::{ActiveStage, StageList};
+use crate::{ActionState, GameSet, GameStates, GameSystems, Player};
+
+/// Directory saved replays are written to.
+pub const REPLAY_DIR: &str = "replays";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Replay {
+    pub seed: u64,
+    /// `FixedUpdate` ticks per second.
+    pub tick_rate: f64,
+    #[serde(default = "default_substeps")]
+    pub substeps: u32,
+    pub win_condition: WinCondition,
+    /// Name of the stage in the [`StageList`].
+    pub stage: String,
+    /// Characters of players `1..=players.len()`.
+    pub players: Vec<ReplayPlayer>,
+    /// Input changes in order of their frame.
+    pub inputs: Vec<InputChange>,
+    /// Number of recorded ticks.
+    pub frames: u32,
+    /// [`StateChecksum`] after the last tick, to detect desyncs.
+    pub checksum: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ReplayPlayer {
+    pub fighter: usize,
+    pub palette: usize,
+}
+
+/// The actions held by `player` from `frame` on.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct InputChange {
+    pub frame: u32,
+    pub player: u32,
+    pub bits: u8,
+    pub stick: Vec2,
+}
+
+fn default_substeps() -> u32 {
+    DEFAULT_SUBSTEPS
+}
+
+impl Replay {
+    pub fn load(path: &Path) -> Result<Replay, String> {
+        let ron = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        ron::from_str(&ron).map_err(|e| e.to_string())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let ron = ron::to_string(self).map_err(|e| e.to_string())?;
+        std::fs::write(path, ron).map_err(|e| e.to_string())
+    }
+}
+
+/// Recording of the current match, or of the last one after it's over.
+#[derive(Resource, Default)]
+pub struct ReplayRecorder {
+    pub replay: Option<Replay>,
+    /// Where the replay was saved, if it was.
+    pub saved: Option<PathBuf>,
+    held: HashMap<u32, (u8, Vec2)>,
+}
+
+/// Feeds a [`Replay`] into the players' [`ActionState`]s instead of the
+/// keyboard and gamepads.
+#[derive(Resource)]
+pub struct ReplayPlayback {
+    pub replay: Replay,
+    /// Whether the checksum after the last recorded tick matches the
+    /// recorded one, once the playback got there.
+    pub in_sync: Option<bool>,
+    /// Index of the next input change to apply.
+    next: usize,
+}
+
+impl ReplayPlayback {
+    pub fn new(replay: Replay) -> Self {
+        ReplayPlayback { replay, in_sync: None, next: 0 }
+    }
+}
+
+/// Sets up the recorded match and starts it, playing back its inputs.
+pub fn start_playback(world: &mut World, replay: Replay) -> Result<(), String> {
+    let stage = world.resource::<StageList>().find(&replay.stage)
+        .ok_or_else(|| format!("unknown stage {}", replay.stage))?
+        .clone();
+    let picks = replay.players.iter().enumerate()
+        .map(|(i, p)| (i as u32 + 1, CharacterPick {
+            fighter: Fighter(p.fighter),
+            palette: p.palette,
+            ready: true,
+        }))
+        .collect();
+    world.insert_resource(MatchSeed(replay.seed));
+    world.insert_resource(PlayerCount(replay.players.len() as u32));
+    world.insert_resource(MatchRules { win_condition: replay.win_condition });
+    world.insert_resource(CharacterSelection { picks });
+    world.insert_resource(ActiveStage(stage));
+    world.insert_resource(Time::<Fixed>::from_hz(replay.tick_rate));
+    world.insert_resource(Substeps(replay.substeps));
+    world.insert_resource(ReplayPlayback::new(replay));
+    world.resource_mut::<NextState<GameStates>>().set(GameStates::Game);
+    Ok(())
+}
+
+fn start_recording(mut recorder: ResMut<ReplayRecorder>,
+                   seed: Res<MatchSeed>,
+                   (time, substeps): (Res<Time<Fixed>>, Res<Substeps>),
+                   rules: Res<MatchRules>,
+                   stage: Res<ActiveStage>,
+                   player_count: Res<PlayerCount>,
+                   selection: Res<CharacterSelection>) {
+    let players = (1..=player_count.0)
+        .map(|player| {
+            let pick = selection.pick(player);
+            ReplayPlayer { fighter: pick.fighter.0, palette: pick.palette }
+        })
+        .collect();
+    *recorder = ReplayRecorder {
+        replay: Some(Replay {
+            seed: seed.0,
+            tick_rate: 1. / time.timestep().as_secs_f64(),
+            substeps: substeps.0,
+            win_condition: rules.win_condition,
+            stage: stage.0.name.clone(),
+            players,
+            inputs: Vec::new(),
+            frames: 0,
+            checksum: 0,
+        }),
+        ..default()
+    };
+}
+
+fn record_inputs(mut recorder: ResMut<ReplayRecorder>,
+                 checksum: Res<StateChecksum>,
+                 query: Query<(&ActionState, &Player)>) {
+    let ReplayRecorder { replay: Some(replay), held, .. } = &mut *recorder else {
+        return;
+    };
+    if checksum.frame <= replay.frames {
+        // The match was rolled back, forget what was recorded since.
+        replay.inputs.retain(|change| change.frame < checksum.frame);
+        held.clear();
+        held.extend(replay.inputs.iter().map(|change| (change.player, (change.bits, change.stick))));
+    }
+    let mut changes: Vec<InputChange> = query.iter()
+        .filter(|(action_state, player)| {
+            held.get(&player.0) != Some(&(action_state.bits(), action_state.stick()))
+        })
+        .map(|(action_state, player)| InputChange {
+            frame: checksum.frame,
+            player: player.0,
+            bits: action_state.bits(),
+            stick: action_state.stick(),
+        })
+        .collect();
+    changes.sort_by_key(|change| change.player);
+    for change in &changes {
+        held.insert(change.player, (change.bits, change.stick));
+    }
+    replay.inputs.extend(changes);
+    replay.frames = checksum.frame;
+    replay.checksum = checksum.hash;
+}
+
+fn restart_playback(mut playback: ResMut<ReplayPlayback>) {
+    playback.next = 0;
+    playback.in_sync = None;
+}
+
+/// Applies the input changes of the upcoming tick.
+fn play_replay(mut playback: ResMut<ReplayPlayback>,
+               checksum: Res<StateChecksum>,
+               mut query: Query<(&mut ActionState, &Player)>) {
+    let frame = checksum.frame + 1;
+    for (mut action_state, _) in &mut query {
+        action_state.advance();
+    }
+    while let Some(change) = playback.replay.inputs.get(playback.next)
+        && change.frame <= frame {
+        for (mut action_state, player) in &mut query {
+            if player.0 == change.player {
+                action_state.set_bits(change.bits);
+                action_state.set_stick(change.stick);
+            }
+        }
+        playback.next += 1;
+    }
+}
+
+fn verify_playback(mut playback: ResMut<ReplayPlayback>, checksum: Res<StateChecksum>) {
+    if checksum.frame != playback.replay.frames {
+        return;
+    }
+    let in_sync = checksum.hash == playback.replay.checksum;
+    playback.in_sync = Some(in_sync);
+    let replay = &playback.replay;
+    if in_sync {
+        info!("replay finished after {} frames", replay.frames);
+    }
+    else {
+        warn!("replay desynced: checksum {:016x} after {} frames, recorded {:016x}",
+              checksum.hash, replay.frames, replay.checksum);
+    }
+}
+
+/// First free `replays/replay-N.replay.ron` path.
+fn replay_path() -> PathBuf {
+    (1..)
+        .map(|n| Path::new(REPLAY_DIR).join(format!("replay-{}.replay.ron", n)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+fn save_replay(mut recorder: ResMut<ReplayRecorder>, keyboard_input: Res<ButtonInput<KeyCode>>) {
+    if !keyboard_input.just_pressed(KeyCode::KeyS) || recorder.saved.is_some() {
+        return;
+    }
+    let Some(replay) = &recorder.replay else {
+        return;
+    };
+    let path = replay_path();
+    match std::fs::create_dir_all(REPLAY_DIR).map_err(|e| e.to_string())
+        .and_then(|_| replay.save(&path)) {
+        Ok(()) => {
+            info!("replay saved to {}", path.display());
+            recorder.saved = Some(path);
+        }
+        Err(e) => { error!("could not save replay to {}: {}", path.display(), e); }
+    }
+}
+
+/// Records every match, saves the recording from the game over screen and
+/// plays back a [`ReplayPlayback`] if there is one.
+pub struct ReplayPlugin;
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ReplayRecorder>();
+        app.add_systems(OnEnter(GameStates::Game), (
+            start_recording,
+            restart_playback.run_if(resource_exists::<ReplayPlayback>),
+        ));
+        app.add_systems(FixedUpdate, (
+            play_replay
+                .run_if(resource_exists::<ReplayPlayback>)
+                .in_set(GameSystems::Input),
+            (record_inputs, verify_playback.run_if(resource_exists::<ReplayPlayback>))
+                .after(compute_checksum)
+                .in_set(GameSystems::Checksum),
+        ).in_set(GameSet));
+        app.add_systems(Update, save_replay.run_if(in_state(GameStates::GameOver)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::state::app::StatesPlugin;
+    use bevy::time::TimeUpdateStrategy;
+    use crate::input::Action;
+    use crate::physics::DEFAULT_TICK_RATE;
+    use crate::sim::{InputScript, ScriptEvent, SimulationPlugin};
+    use crate::{
+        CollisionPlugin, CombatPlugin, DeterminismPlugin, InputPlugin, MatchPlugin, PhysicsPlugin,
+        PlatformPlugin,
+    };
+
+    const FRAMES: u32 = 300;
+
+    /// A windowless app which runs one tick per update and records replays.
+    fn replay_app(recording: bool) -> App {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, StatesPlugin));
+        app.add_plugins((MatchPlugin, PhysicsPlugin, CollisionPlugin, PlatformPlugin, CombatPlugin,
+                         InputPlugin, DeterminismPlugin, ReplayPlugin));
+        if recording {
+            app.add_plugins(SimulationPlugin);
+        }
+        let fixed_time = Time::<Fixed>::from_hz(DEFAULT_TICK_RATE);
+        app.insert_resource(TimeUpdateStrategy::ManualDuration(fixed_time.timestep()));
+        app.insert_resource(fixed_time);
+        app.finish();
+        app.cleanup();
+        app
+    }
+
+    fn script() -> InputScript {
+        let event = |frame, player, press, release| ScriptEvent { frame, player, press, release };
+        InputScript {
+            events: vec![
+                event(0, 1, vec![Action::Left], vec![]),
+                event(0, 2, vec![Action::Right], vec![]),
+                event(15, 1, vec![Action::Attack], vec![Action::Left]),
+                event(20, 2, vec![Action::Jump, Action::Attack], vec![Action::Right]),
+                event(40, 1, vec![Action::Special], vec![Action::Attack]),
+            ],
+        }
+    }
+
+    #[test]
+    fn recorded_match_plays_back_in_sync() {
+        let mut app = replay_app(true);
+        app.insert_resource(MatchSeed(3));
+        app.insert_resource(script());
+        app.world_mut().resource_mut::<NextState<GameStates>>().set(GameStates::Game);
+        while app.world().resource::<StateChecksum>().frame < FRAMES {
+            app.update();
+        }
+        let recorded = app.world().resource::<ReplayRecorder>().replay.clone().unwrap();
+        assert_eq!(recorded.frames, FRAMES);
+        assert!(!recorded.inputs.is_empty());
+
+        let path = std::env::temp_dir().join(format!("replay-test-{}.replay.ron", std::process::id()));
+        recorded.save(&path).unwrap();
+        let loaded = Replay::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let mut app = replay_app(false);
+        start_playback(app.world_mut(), loaded.unwrap()).unwrap();
+        while app.world().resource::<StateChecksum>().frame < FRAMES {
+            app.update();
+        }
+        assert_eq!(app.world().resource::<ReplayPlayback>().in_sync, Some(true));
+    }
+}
@@ -0,0 +1,481 @@
+//! Headless simulation of a match, driven by scripted inputs.
+
+use bevy::platform::collections::HashMap;
+use std::collections::BTreeMap;
+use bevy::prelude::*;
+use bevy::state::app::StatesPlugin;
+use bevy::time::TimeUpdateStrategy;
+use serde::{Deserialize, Serialize};
+
+use crate::cpu::{Cpu, CpuPlayers, CpuPlugin};
+use crate::determinism::{MatchSeed, StateChecksum};
+use crate::game_match::{KoEvent, Kos, MatchRules, MatchStats, PlayerCount, PlayerResult};
+use crate::input::{read_local_input, Action, ActionState};
+use crate::netcode::{
+    advance_session, read_net_input, start_session, FrameInput, LocalInput, LoopbackTransport,
+    NetSession, NetStats, NetcodePlugin, DEFAULT_INPUT_DELAY,
+};
+use crate::physics::{Substeps, Velocity, DEFAULT_TICK_RATE};
+use crate::stage::{ActiveStage, BlastSide, Stage};
+use crate::{
+    CollisionPlugin, CombatPlugin, Damage, DeterminismPlugin, GameSet, GameStates, GameSystems,
+    InputPlugin, MatchPlugin, PhysicsPlugin, PlatformPlugin, Player, Score,
+};
+
+/// Scripted inputs, e.g.
+/// `{"events": [{"frame": 0, "player": 1, "press": ["left"]},
+///              {"frame": 30, "player": 1, "release": ["left"]}]}`.
+/// Pressed actions stay held until they are released.
+#[derive(Resource, Deserialize, Default, Clone)]
+pub struct InputScript {
+    #[serde(default)]
+    pub events: Vec<ScriptEvent>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ScriptEvent {
+    pub frame: u32,
+    pub player: u32,
+    #[serde(default)]
+    pub press: Vec<Action>,
+    #[serde(default)]
+    pub release: Vec<Action>,
+}
+
+/// Number of `FixedUpdate` ticks simulated so far.
+#[derive(Resource, Default)]
+pub struct SimFrame(pub u32);
+
+pub struct SimulationConfig {
+    /// Number of ticks to simulate.
+    pub frames: u32,
+    pub seed: u64,
+    pub players: u32,
+    pub rules: MatchRules,
+    pub stage: Stage,
+    /// `FixedUpdate` ticks per second.
+    pub tick_rate: f64,
+    pub substeps: Substeps,
+    pub script: InputScript,
+    /// Players controlled by the CPU instead of the script.
+    pub cpus: CpuPlayers,
+    /// Whether to report the state checksum of every tick.
+    pub checksums: bool,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            frames: 3600,
+            seed: 0,
+            players: 2,
+            rules: MatchRules::default(),
+            stage: ActiveStage::default().0,
+            tick_rate: DEFAULT_TICK_RATE,
+            substeps: Substeps::default(),
+            script: InputScript::default(),
+            cpus: CpuPlayers::default(),
+            checksums: false,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct PlayerReport {
+    pub player: u32,
+    pub score: u32,
+    pub kos: u32,
+    pub damage: f32,
+    pub position: [f32; 2],
+    pub velocity: [f32; 2],
+    pub stats: MatchStats,
+}
+
+#[derive(Serialize, Clone)]
+pub struct KoReport {
+    pub frame: u32,
+    pub player: u32,
+    pub ko_by: Option<u32>,
+    pub side: BlastSide,
+}
+
+/// KOs of the simulated match so far.
+#[derive(Resource, Default)]
+pub struct KoLog(pub Vec<KoReport>);
+
+#[derive(Serialize)]
+pub struct SimulationReport {
+    pub seed: u64,
+    pub frames: u32,
+    pub seconds: f32,
+    pub game_over: bool,
+    pub winner: Option<u32>,
+    /// Players still in the match; players who were knocked out are missing.
+    pub players: Vec<PlayerReport>,
+    pub kos: Vec<KoReport>,
+    /// [`StateChecksum`] after the last tick, as hex.
+    pub checksum: String,
+    /// Checksums of all ticks, if requested.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub checksums: Vec<String>,
+}
+
+pub(crate) fn apply_script(script: Res<InputScript>,
+                mut frame: ResMut<SimFrame>,
+                mut held: Local<HashMap<u32, ActionState>>,
+                mut query: Query<(&mut ActionState, &Player), Without<Cpu>>) {
+    for event in script.events.iter().filter(|e| e.frame == frame.0) {
+        let actions = held.entry(event.player).or_default();
+        for action in &event.release {
+            actions.release(*action);
+        }
+        for action in &event.press {
+            actions.press(*action);
+        }
+    }
+    for (mut action_state, player) in &mut query {
+        let bits = held.get(&player.0).map_or(0, ActionState::bits);
+        action_state.set_bits(bits);
+    }
+    frame.0 += 1;
+}
+
+fn log_ko(event: On<KoEvent>, frame: Res<SimFrame>, mut log: ResMut<KoLog>) {
+    log.0.push(KoReport {
+        // The frame counter was already advanced in the input step.
+        frame: frame.0.saturating_sub(1),
+        player: event.player,
+        ko_by: event.ko_by,
+        side: event.side,
+    });
+}
+
+/// Feeds an [`InputScript`] into the players' [`ActionState`]s, one tick at a time.
+pub struct SimulationPlugin;
+impl Plugin for SimulationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<InputScript>();
+        app.init_resource::<SimFrame>();
+        app.init_resource::<KoLog>();
+        app.add_observer(log_ko);
+        app.add_systems(FixedUpdate, apply_script
+                        .after(read_local_input)
+                        .in_set(GameSystems::Input)
+                        .in_set(GameSet));
+    }
+}
+
+/// Builds a windowless app which runs exactly one `FixedUpdate` tick per
+/// `update` and starts straight into a match.
+pub fn headless_app(config: &SimulationConfig) -> App {
+    let mut app = App::new();
+    app.add_plugins((MinimalPlugins, StatesPlugin));
+    app.add_plugins((MatchPlugin, PhysicsPlugin, CollisionPlugin, PlatformPlugin, CombatPlugin,
+                     InputPlugin, DeterminismPlugin, CpuPlugin, SimulationPlugin));
+    let fixed_time = Time::<Fixed>::from_hz(config.tick_rate);
+    app.insert_resource(TimeUpdateStrategy::ManualDuration(fixed_time.timestep()));
+    app.insert_resource(fixed_time);
+    app.insert_resource(config.substeps);
+    app.insert_resource(MatchSeed(config.seed));
+    app.insert_resource(PlayerCount(config.players));
+    app.insert_resource(config.rules.clone());
+    app.insert_resource(ActiveStage(config.stage.clone()));
+    app.insert_resource(config.script.clone());
+    app.insert_resource(config.cpus.clone());
+    app.world_mut().resource_mut::<NextState<GameStates>>().set(GameStates::Game);
+    app.finish();
+    app.cleanup();
+    app
+}
+
+pub(crate) fn game_over_pending(app: &App) -> bool {
+    matches!(app.world().resource::<NextState<GameStates>>(),
+             NextState::Pending(GameStates::GameOver))
+}
+
+/// Runs `config.frames` ticks, or until the match is over, and reports the
+/// final state of all players.
+pub fn run_simulation(config: &SimulationConfig) -> SimulationReport {
+    let mut app = headless_app(config);
+    let mut checksums = Vec::new();
+    while app.world().resource::<SimFrame>().0 < config.frames && !game_over_pending(&app) {
+        app.update();
+        if config.checksums {
+            checksums.push(format!("{:016x}", app.world().resource::<StateChecksum>().hash));
+        }
+    }
+
+    let world = app.world_mut();
+    let mut players: Vec<PlayerReport> = world
+        .query::<(&Player, &Score, &Kos, &Damage, &Transform, &Velocity, &MatchStats)>()
+        .iter(world)
+        .map(|(player, score, kos, damage, tf, v, stats)| PlayerReport {
+            player: player.0,
+            score: score.0,
+            kos: kos.0,
+            damage: damage.0,
+            position: tf.translation.truncate().to_array(),
+            velocity: v.0.truncate().to_array(),
+            stats: stats.clone(),
+        })
+        .collect();
+    players.sort_by_key(|p| p.player);
+
+    // Commands of the last tick, e.g. the results of a finished match, are
+    // only applied by the next update.
+    world.flush();
+    let winner = world.query::<&PlayerResult>()
+        .iter(world)
+        .find(|result| result.place == 1)
+        .map(|result| result.player);
+    let frames = world.resource::<SimFrame>().0;
+    let kos = world.resource::<KoLog>().0.clone();
+    let checksum = format!("{:016x}", world.resource::<StateChecksum>().hash);
+    SimulationReport {
+        seed: config.seed,
+        frames,
+        seconds: frames as f32 / config.tick_rate as f32,
+        game_over: game_over_pending(&app),
+        winner,
+        kos,
+        checksum,
+        checksums,
+        players,
+    }
+}
+
+pub struct LoopbackConfig {
+    /// Number of ticks both peers need to confirm.
+    pub frames: u32,
+    pub seed: u64,
+    /// Ticks until a packet arrives.
+    pub latency: u32,
+    /// Chance of a packet getting lost, from 0 to 1.
+    pub loss: f32,
+    pub input_delay: u32,
+    pub tick_rate: f64,
+    /// Inputs of both players, each peer plays its own player's part.
+    pub script: InputScript,
+}
+
+impl Default for LoopbackConfig {
+    fn default() -> Self {
+        Self {
+            frames: 3600,
+            seed: 0,
+            latency: 5,
+            loss: 0.1,
+            input_delay: DEFAULT_INPUT_DELAY,
+            tick_rate: DEFAULT_TICK_RATE,
+            script: InputScript::default(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct PeerReport {
+    pub player: u32,
+    /// Ticks simulated, including predicted ones.
+    pub frames: u32,
+    pub confirmed: u32,
+    pub rollbacks: u32,
+    pub resimulated_frames: u32,
+    pub stalls: u32,
+    /// Checksums compared with the other peer over the connection.
+    pub compared_frames: u32,
+    pub desyncs: u32,
+}
+
+#[derive(Serialize)]
+pub struct LoopbackReport {
+    pub latency: u32,
+    pub loss: f32,
+    /// Ticks run by each peer, including the ones spent waiting.
+    pub ticks: u32,
+    /// Confirmed ticks of which the harness compared the checksums of both peers.
+    pub compared_frames: u32,
+    pub mismatched_frames: u32,
+    pub first_mismatch: Option<u32>,
+    pub peers: Vec<PeerReport>,
+}
+
+/// Feeds the local player's part of the [`InputScript`] into the session.
+fn script_local_input(script: Res<InputScript>,
+                      session: Res<NetSession>,
+                      checksum: Res<StateChecksum>,
+                      mut held: Local<ActionState>,
+                      mut local_input: ResMut<LocalInput>) {
+    let events = script.events.iter()
+        .filter(|e| e.frame == checksum.frame && e.player == session.local_player);
+    for event in events {
+        for action in &event.release {
+            held.release(*action);
+        }
+        for action in &event.press {
+            held.press(*action);
+        }
+    }
+    local_input.0 = FrameInput::from(&*held);
+}
+
+/// A windowless peer of a two-player session, like [`headless_app`].
+fn peer_app(config: &LoopbackConfig, transport: LoopbackTransport, player: u32) -> App {
+    let mut app = App::new();
+    app.add_plugins((MinimalPlugins, StatesPlugin));
+    app.add_plugins((MatchPlugin, PhysicsPlugin, CollisionPlugin, PlatformPlugin, CombatPlugin,
+                     InputPlugin, DeterminismPlugin, NetcodePlugin));
+    app.add_systems(FixedPreUpdate, script_local_input
+                    .after(read_net_input)
+                    .before(advance_session)
+                    .run_if(in_state(GameStates::Game)));
+    let fixed_time = Time::<Fixed>::from_hz(config.tick_rate);
+    app.insert_resource(TimeUpdateStrategy::ManualDuration(fixed_time.timestep()));
+    app.insert_resource(fixed_time);
+    app.insert_resource(MatchSeed(config.seed));
+    app.insert_resource(config.script.clone());
+    start_session(app.world_mut(), NetSession::new(transport, player, config.input_delay));
+    app.finish();
+    app.cleanup();
+    app
+}
+
+fn session(app: &App) -> &NetSession {
+    app.world().resource::<NetSession>()
+}
+
+/// Runs two peers connected by a [`LoopbackTransport`] in lockstep until both
+/// confirmed `config.frames` ticks, and compares their checksums.
+pub fn run_loopback(config: &LoopbackConfig) -> LoopbackReport {
+    let (transport1, transport2) = LoopbackTransport::pair(config.latency, config.loss, config.seed);
+    let mut peers = [peer_app(config, transport1, 1), peer_app(config, transport2, 2)];
+    let mut checksums: [BTreeMap<u32, u64>; 2] = Default::default();
+    // Enough to get through long streaks of lost packets, but not forever.
+    let max_ticks = config.frames * 4 + 100;
+    let mut ticks = 0;
+    while ticks < max_ticks
+        && peers.iter().any(|app| session(app).confirmed() < config.frames)
+        && !peers.iter().any(game_over_pending) {
+        for (app, checksums) in peers.iter_mut().zip(&mut checksums) {
+            app.update();
+            checksums.extend(session(app).confirmed_checksums());
+        }
+        ticks += 1;
+    }
+
+    let mismatches: Vec<u32> = checksums[0].iter()
+        .filter(|(frame, hash)| checksums[1].get(frame).is_some_and(|other| other != *hash))
+        .map(|(frame, _)| *frame)
+        .collect();
+    let compared_frames = checksums[0].keys().filter(|f| checksums[1].contains_key(f)).count();
+    let peers = peers.iter()
+        .map(|app| {
+            let session = session(app);
+            let NetStats { rollbacks, resimulated_frames, stalls, compared_frames, desyncs } =
+                session.stats;
+            PeerReport {
+                player: session.local_player,
+                frames: app.world().resource::<StateChecksum>().frame,
+                confirmed: session.confirmed(),
+                rollbacks,
+                resimulated_frames,
+                stalls,
+                compared_frames,
+                desyncs,
+            }
+        })
+        .collect();
+    LoopbackReport {
+        latency: config.latency,
+        loss: config.loss,
+        ticks,
+        compared_frames: compared_frames as u32,
+        mismatched_frames: mismatches.len() as u32,
+        first_mismatch: mismatches.first().copied(),
+        peers,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_match::WinCondition;
+
+    /// Both players walk into each other and attack, so rollbacks have hits
+    /// and hitboxes to restore.
+    fn fight_script() -> InputScript {
+        let event = |frame, player, press, release| ScriptEvent { frame, player, press, release };
+        InputScript {
+            events: vec![
+                event(0, 1, vec![Action::Left], vec![]),
+                event(0, 2, vec![Action::Right], vec![]),
+                event(20, 1, vec![Action::Attack], vec![Action::Left]),
+                event(20, 2, vec![Action::Attack], vec![Action::Right]),
+                event(30, 1, vec![], vec![Action::Attack]),
+                event(30, 2, vec![], vec![Action::Attack]),
+                event(40, 1, vec![Action::Attack, Action::Jump], vec![]),
+                event(42, 2, vec![Action::Attack, Action::Special], vec![]),
+                event(60, 1, vec![Action::Right], vec![Action::Attack, Action::Jump]),
+                event(90, 2, vec![Action::Left], vec![Action::Attack, Action::Special]),
+            ],
+        }
+    }
+
+    #[test]
+    fn loopback_peers_stay_in_sync() {
+        let report = run_loopback(&LoopbackConfig {
+            frames: 600,
+            latency: 6,
+            loss: 0.2,
+            script: fight_script(),
+            ..default()
+        });
+        assert!(report.compared_frames > 0);
+        assert_eq!(report.mismatched_frames, 0, "first mismatch at {:?}", report.first_mismatch);
+        for peer in &report.peers {
+            assert_eq!(peer.confirmed, 600);
+            assert!(peer.rollbacks > 0);
+            assert_eq!(peer.desyncs, 0);
+        }
+    }
+
+    /// A one-stock match in which player 2 keeps changing inputs, so
+    /// rollbacks cross the tick the match is decided in.
+    #[test]
+    fn loopback_peers_agree_on_the_winner() {
+        let event = |frame, player, press, release| ScriptEvent { frame, player, press, release };
+        let mut events = vec![event(0, 1, vec![Action::Left], vec![])];
+        events.extend((1..200).map(|n| match n % 2 {
+            0 => event(n * 3, 2, vec![], vec![Action::Attack]),
+            _ => event(n * 3, 2, vec![Action::Attack], vec![]),
+        }));
+        let config = LoopbackConfig { latency: 6, loss: 0.2, script: InputScript { events }, ..default() };
+        let (transport1, transport2) = LoopbackTransport::pair(config.latency, config.loss, config.seed);
+        let mut peers = [peer_app(&config, transport1, 1), peer_app(&config, transport2, 2)];
+        for app in &mut peers {
+            app.insert_resource(MatchRules { win_condition: WinCondition::Stock(1) });
+        }
+        for _ in 0..2000 {
+            if peers.iter().all(game_over_pending) {
+                break;
+            }
+            for app in peers.iter_mut().filter(|app| !game_over_pending(app)) {
+                app.update();
+            }
+        }
+
+        let results: Vec<Vec<(u32, u32)>> = peers.iter_mut()
+            .map(|app| {
+                assert!(game_over_pending(app), "match ended on both peers");
+                assert!(session(app).stats.rollbacks > 0);
+                let mut places: Vec<(u32, u32)> = app.world_mut().query::<&PlayerResult>()
+                    .iter(app.world())
+                    .map(|result| (result.place, result.player))
+                    .collect();
+                places.sort_unstable();
+                places
+            })
+            .collect();
+        assert_eq!(results[0].len(), 2);
+        assert_eq!(results[0], results[1]);
+    }
+}
@@ -0,0 +1,288 @@
+//! Stages are described by `.stage.ron` asset files, see
+//! `assets/stages/classic.stage.ron`. The built-in stages are compiled in,
+//! so headless apps can use them without an `AssetServer`, and replaced by
+//! the asset files once those are loaded. Other files in `assets/stages`
+//! are added to the stage select, no recompile needed.
+
+use bevy::asset::io::Reader;
+use bevy::asset::{AssetLoader, LoadContext, LoadedFolder};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::platform::{PlatformPath, PlatformTimer};
+
+const DEFAULT_PLATFORM_COLOR: (f32, f32, f32) = (0.7, 0.7, 1.0);
+/// Asset paths and contents of the built-in stages.
+const BUILT_IN_STAGES: [(&str, &str); 4] = [
+    ("stages/final.stage.ron", include_str!("../assets/stages/final.stage.ron")),
+    ("stages/classic.stage.ron", include_str!("../assets/stages/classic.stage.ron")),
+    ("stages/tower.stage.ron", include_str!("../assets/stages/tower.stage.ron")),
+    ("stages/drift.stage.ron", include_str!("../assets/stages/drift.stage.ron")),
+];
+/// Asset folder the stage files are loaded from.
+const STAGE_FOLDER: &str = "stages";
+/// Index of the stage matches are played on by default.
+const DEFAULT_STAGE: usize = 1;
+
+#[derive(Asset, TypePath, Debug, Clone, Deserialize)]
+pub struct Stage {
+    pub name: String,
+    pub platforms: Vec<StagePlatform>,
+    #[serde(default)]
+    pub spawn_points: Vec<Vec2>,
+    pub respawn_point: Vec2,
+    pub blast_zone: BlastZone,
+    pub background: Background,
+    /// Asset path of the soundtrack.
+    pub music: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StagePlatform {
+    pub position: Vec2,
+    pub size: Vec2,
+    /// Whether players can jump up and drop down through the platform.
+    #[serde(default)]
+    pub one_way: bool,
+    /// Path the platform travels along, if it moves.
+    #[serde(default)]
+    pub path: Option<PlatformPath>,
+    /// When the platform is there, if it comes and goes.
+    #[serde(default)]
+    pub timer: Option<PlatformTimer>,
+    #[serde(default = "default_platform_color")]
+    pub color: (f32, f32, f32),
+}
+
+fn default_platform_color() -> (f32, f32, f32) {
+    DEFAULT_PLATFORM_COLOR
+}
+
+/// Area outside of which players are KO'd.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct BlastZone {
+    pub min: Vec2,
+    pub max: Vec2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlastSide {
+    Left,
+    Right,
+    Top,
+    Bottom,
+}
+
+impl BlastZone {
+    /// The side of the blast zone `position` is beyond, if any. Corners count
+    /// as bottom or top.
+    pub fn crossed(&self, position: Vec2) -> Option<BlastSide> {
+        if position.y < self.min.y {
+            Some(BlastSide::Bottom)
+        }
+        else if position.y > self.max.y {
+            Some(BlastSide::Top)
+        }
+        else if position.x < self.min.x {
+            Some(BlastSide::Left)
+        }
+        else if position.x > self.max.x {
+            Some(BlastSide::Right)
+        }
+        else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Background {
+    pub color: (f32, f32, f32),
+    #[serde(default)]
+    pub decorations: Vec<Decoration>,
+}
+
+/// Rectangle without collision, e.g. water.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Decoration {
+    pub position: Vec2,
+    pub size: Vec2,
+    pub color: (f32, f32, f32),
+    /// Z coordinate, positive values are drawn in front of the players.
+    #[serde(default)]
+    pub depth: f32,
+}
+
+pub fn rgb((r, g, b): (f32, f32, f32)) -> Color {
+    Color::srgb(r, g, b)
+}
+
+impl Stage {
+    pub fn from_ron(ron: &str) -> Result<Self, ron::error::SpannedError> {
+        ron::from_str(ron)
+    }
+
+    /// Where player `player` of `player_count` players enters the stage.
+    /// Without enough spawn points, players are spread evenly around the
+    /// respawn point, player 1 on the right.
+    pub fn spawn_point(&self, player: u32, player_count: u32) -> Vec3 {
+        if self.spawn_points.len() >= player_count as usize {
+            return self.spawn_points[player as usize - 1].extend(0.);
+        }
+        let spacing = if player_count <= 3 { 200. } else { 500. / (player_count - 1) as f32 };
+        let x = spacing * ((player_count - 1) as f32 / 2. - (player - 1) as f32);
+        (self.respawn_point + Vec2::new(x, 0.)).extend(0.)
+    }
+}
+
+/// Stages which can be picked in the stage select.
+#[derive(Resource, Clone)]
+pub struct StageList {
+    pub stages: Vec<Stage>,
+    /// Asset path of each stage, in the same order.
+    paths: Vec<String>,
+}
+
+impl Default for StageList {
+    fn default() -> Self {
+        let stages = BUILT_IN_STAGES.iter()
+            .map(|(path, ron)| Stage::from_ron(ron)
+                .unwrap_or_else(|e| panic!("built-in stage {} is invalid: {}", path, e)))
+            .collect();
+        let paths = BUILT_IN_STAGES.iter().map(|(path, _)| path.to_string()).collect();
+        StageList { stages, paths }
+    }
+}
+
+impl StageList {
+    pub fn find(&self, name: &str) -> Option<&Stage> {
+        self.stages.iter().find(|stage| stage.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Replaces the stage loaded from `path`, or adds it after the others if
+    /// it's new.
+    pub fn insert(&mut self, path: String, stage: Stage) {
+        match self.paths.iter().position(|p| *p == path) {
+            Some(index) => { self.stages[index] = stage; }
+            None => {
+                self.stages.push(stage);
+                self.paths.push(path);
+            }
+        }
+    }
+}
+
+/// Entry picked in the stage select.
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub enum StageChoice {
+    /// Index into [`StageList`].
+    Stage(usize),
+    Random,
+}
+
+impl Default for StageChoice {
+    fn default() -> Self {
+        StageChoice::Stage(DEFAULT_STAGE)
+    }
+}
+
+impl StageChoice {
+    /// The entry `step` places further, with random after the last stage.
+    pub fn cycle(self, step: i32, stage_count: usize) -> StageChoice {
+        let index = match self {
+            StageChoice::Stage(index) => index,
+            StageChoice::Random => stage_count,
+        };
+        match (index as i32 + step).rem_euclid(stage_count as i32 + 1) as usize {
+            index if index == stage_count => StageChoice::Random,
+            index => StageChoice::Stage(index),
+        }
+    }
+}
+
+/// The stage the next match is played on.
+#[derive(Resource, Clone)]
+pub struct ActiveStage(pub Stage);
+
+impl Default for ActiveStage {
+    fn default() -> Self {
+        ActiveStage(StageList::default().stages.swap_remove(DEFAULT_STAGE))
+    }
+}
+
+#[derive(Debug)]
+pub enum StageLoaderError {
+    Io(std::io::Error),
+    Ron(ron::error::SpannedError),
+}
+
+impl fmt::Display for StageLoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StageLoaderError::Io(e) => { write!(f, "could not read stage: {}", e) }
+            StageLoaderError::Ron(e) => { write!(f, "invalid stage: {}", e) }
+        }
+    }
+}
+
+impl std::error::Error for StageLoaderError {}
+
+#[derive(Default, TypePath)]
+pub struct StageLoader;
+
+impl AssetLoader for StageLoader {
+    type Asset = Stage;
+    type Settings = ();
+    type Error = StageLoaderError;
+
+    async fn load(&self,
+                  reader: &mut dyn Reader,
+                  _settings: &(),
+                  _load_context: &mut LoadContext<'_>) -> Result<Stage, StageLoaderError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await.map_err(StageLoaderError::Io)?;
+        ron::de::from_bytes(&bytes).map_err(StageLoaderError::Ron)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["stage.ron"]
+    }
+}
+
+/// The folder of stage files, which keeps the stages in it loaded.
+#[derive(Resource)]
+pub struct StageFolder(pub Handle<LoadedFolder>);
+
+fn load_stages(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(StageFolder(asset_server.load_folder(STAGE_FOLDER)));
+}
+
+/// Puts each stage file into the [`StageList`] once it's loaded, and again
+/// whenever it changes on disk.
+fn apply_stages(mut events: MessageReader<AssetEvent<Stage>>,
+                asset_server: Res<AssetServer>,
+                stages: Res<Assets<Stage>>,
+                mut stage_list: ResMut<StageList>) {
+    for event in events.read() {
+        if let AssetEvent::LoadedWithDependencies { id } | AssetEvent::Modified { id } = event
+            && let Some(stage) = stages.get(*id)
+            && let Some(path) = asset_server.get_path(*id) {
+            info!("stage {} loaded from {}", stage.name, path);
+            stage_list.insert(path.to_string(), stage.clone());
+        }
+    }
+}
+
+/// Loads stages from asset files, needs bevy's `AssetPlugin`.
+pub struct StagePlugin;
+impl Plugin for StagePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<Stage>();
+        app.init_asset_loader::<StageLoader>();
+        app.init_resource::<StageList>();
+        app.add_systems(Startup, load_stages);
+        app.add_systems(Update, apply_stages.run_if(resource_exists::<StageFolder>));
+    }
+}
@@ -0,0 +1,597 @@
+use bevy::prelude::*;
+use bevy::text::LineBreak;
+
+use crate::game_match::{Kos, MatchProgress, MatchRules, PlayerCount, Score, WinCondition};
+use crate::character::{CharacterRegistry, CharacterSelection, Fighter, Tint};
+use crate::cpu::CpuPlayers;
+use crate::combat::Damage;
+use crate::input::Cooldown;
+use crate::lobby::LOBBY_SIZE;
+use crate::online::LobbyClient;
+use crate::physics::Velocity;
+use crate::replay::ReplayRecorder;
+use crate::stage::{rgb, ActiveStage, StageChoice, StageList};
+use crate::{GameSet, GameStates, GameSystems, MatchPhase, PauseState, Player, PlayerResult};
+
+const BACKGROUND_COLOR: Color = Color::srgb(0.9, 0.9, 0.9);
+const SPECIAL_MOVE_TINT: Color = Color::srgb(1.0, 0.7, 0.7);
+
+#[derive(Component)]
+pub struct MenuText;
+
+#[derive(Component)]
+pub struct StageSelectText;
+
+/// Lobbies to join, or the joined lobby, in the online menu.
+#[derive(Component)]
+pub struct LobbyText;
+
+/// Portrait of the character picked by a player in the character select.
+#[derive(Component)]
+pub struct CharacterPortrait(pub u32);
+
+/// Name and ready state of a player's pick in the character select.
+#[derive(Component)]
+pub struct CharacterPickText(pub u32);
+
+/// Shows the stocks left of a player, or their KOs if the match isn't played
+/// with stocks.
+#[derive(Component)]
+pub struct ScoreDisplay(pub u32);
+
+/// Shows the time left of a timed match, and when sudden death starts.
+#[derive(Component)]
+pub struct ClockDisplay;
+
+/// Shows the damage percent of a player next to their score.
+#[derive(Component)]
+pub struct DamageDisplay(pub u32);
+
+#[derive(Component)]
+pub struct GameOverText;
+
+/// Tells whether the replay of the last match was saved.
+#[derive(Component)]
+pub struct ReplayStatusText;
+
+fn show_score(players: Query<(&Score, &Kos, &Player)>,
+              rules: Res<MatchRules>,
+              phase: Res<State<MatchPhase>>,
+              mut displays: Query<(&mut Text, &ScoreDisplay)>) {
+    let stocks = match (phase.get(), rules.win_condition) {
+        (MatchPhase::SuddenDeath, _) => Some(1),
+        (MatchPhase::Regular, WinCondition::Stock(stocks)) => Some(stocks),
+        _ => None,
+    };
+    for (score, kos, player) in &players {
+        for (mut text, display) in &mut displays {
+            if display.0 == player.0 {
+                let value = stocks.map_or(kos.0, |stocks| stocks.saturating_sub(score.0));
+                text.set_if_neq(Text(value.to_string()));
+            }
+        }
+    }
+}
+
+fn show_clock(rules: Res<MatchRules>,
+              progress: Res<MatchProgress>,
+              phase: Res<State<MatchPhase>>,
+              mut query: Query<&mut Text, With<ClockDisplay>>) {
+    let clock = match (phase.get(), rules.win_condition) {
+        (MatchPhase::SuddenDeath, _) => "Sudden Death!".to_string(),
+        (MatchPhase::Regular, WinCondition::Timed(secs)) => {
+            let left = (secs - progress.elapsed).max(0.).ceil() as u32;
+            format!("{}:{:02}", left / 60, left % 60)
+        }
+        _ => String::new(),
+    };
+    for mut text in &mut query {
+        text.set_if_neq(Text(clock.clone()));
+    }
+}
+
+fn show_damage(players: Query<(&Damage, &Player), Changed<Damage>>,
+               mut displays: Query<(&mut Text, &DamageDisplay)>) {
+    for (damage, player) in &players {
+        for (mut text, display) in &mut displays {
+            if display.0 == player.0 {
+                **text = format!("{:.0}%", damage.0);
+            }
+        }
+    }
+}
+
+fn flip_sprite(mut query: Query<(&mut Sprite, &Velocity), With<Player>>) {
+    for (mut sprite, v) in &mut query {
+        if v.0.x.abs() >= 320. {
+            sprite.flip_x = v.0.x > 0.;
+        }
+    }
+}
+
+/// Name of the character of a player, with the player number if several
+/// players picked the same character.
+fn result_name(registry: &CharacterRegistry, results: &[&PlayerResult], result: &PlayerResult) -> String {
+    let name = registry.name(result.fighter);
+    if results.iter().filter(|other| other.fighter == result.fighter).count() > 1 {
+        format!("{} (P{})", name, result.player)
+    }
+    else {
+        name.to_string()
+    }
+}
+
+fn skin_players(mut query: Query<(&mut Sprite, &Fighter), Added<Player>>,
+                registry: Res<CharacterRegistry>,
+                asset_server: Res<AssetServer>) {
+    for (mut sprite, fighter) in &mut query {
+        sprite.image = asset_server.load(&registry.get(*fighter).sprite);
+    }
+}
+
+fn tint_players(mut query: Query<(&mut Sprite, &Cooldown, &Tint)>) {
+    for (mut sprite, cooldown, tint) in &mut query {
+        sprite.color = if cooldown.charge { tint.0 } else { SPECIAL_MOVE_TINT };
+    }
+}
+
+fn game_over_screen(
+    query_player_results: Query<&PlayerResult>,
+    registry: Res<CharacterRegistry>,
+    mut commands: Commands, asset_server: Res<AssetServer>) {
+    let font: Handle<Font> = asset_server.load("fonts/terminal-grotesque.ttf");
+    let mut results: Vec<&PlayerResult> = query_player_results.iter().collect();
+    results.sort_by_key(|result| result.place);
+    let winner_string = results.first()
+        .map_or("???".to_string(), |result| result_name(&registry, &results, result));
+    let stats_string = results.iter()
+        .map(|result| format!(
+            "{}. {}  KOs {}  Falls {} ({} SD)  Damage {:.0}%  Specials {}  Airborne {:.1}s",
+            result.place,
+            result_name(&registry, &results, result),
+            result.stats.kos,
+            result.stats.falls,
+            result.stats.self_destructs,
+            result.stats.damage_dealt,
+            result.stats.special_moves,
+            result.stats.airborne_secs,
+        ))
+        .collect::<Vec<_>>()
+        .join("\n");
+    commands.spawn((
+        DespawnOnExit(GameStates::GameOver),
+        GameOverText,
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(100.0),
+            height: Val::Px(400.),
+            width: percent(100),
+            ..default()
+        },
+        Text(format!("{} wins!\n [R]ematch? [S]ave replay", winner_string)),
+        TextLayout::new(Justify::Center, LineBreak::AnyCharacter),
+        TextColor(Color::BLACK),
+        TextFont {
+            font: font.clone(),
+            font_size: 128.,
+            ..default()
+        },
+    ));
+    commands.spawn((
+        DespawnOnExit(GameStates::GameOver),
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(520.0),
+            width: percent(100),
+            ..default()
+        },
+        Text(stats_string),
+        TextLayout::new(Justify::Center, LineBreak::WordBoundary),
+        TextColor(Color::BLACK),
+        TextFont {
+            font: font.clone(),
+            font_size: 32.,
+            ..default()
+        },
+    ));
+    commands.spawn((
+        DespawnOnExit(GameStates::GameOver),
+        ReplayStatusText,
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(40.0),
+            width: percent(100),
+            ..default()
+        },
+        Text::default(),
+        TextLayout::new(Justify::Center, LineBreak::WordBoundary),
+        TextColor(Color::BLACK),
+        TextFont {
+            font,
+            font_size: 32.,
+            ..default()
+        },
+    ));
+}
+
+fn show_replay_status(recorder: Res<ReplayRecorder>,
+                      mut query: Query<&mut Text, With<ReplayStatusText>>) {
+    let status = recorder.saved.as_ref()
+        .map_or(String::new(), |path| format!("Replay saved to {}", path.display()));
+    for mut text in &mut query {
+        text.set_if_neq(Text(status.clone()));
+    }
+}
+
+fn pause_screen(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let font: Handle<Font> = asset_server.load("fonts/terminal-grotesque.ttf");
+    commands.spawn((
+        DespawnOnExit(PauseState::Paused),
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(100.0),
+            width: percent(100),
+            ..default()
+        },
+        Text::new("Paused\n[Enter]/[Start] to resume"),
+        TextLayout::new(Justify::Center, LineBreak::WordBoundary),
+        TextColor(Color::BLACK),
+        TextFont {
+            font,
+            font_size: 64.,
+            ..default()
+        },
+    ));
+}
+
+fn spawn_score_display(mut commands: Commands,
+                       player_count: Res<PlayerCount>,
+                       asset_server: Res<AssetServer>) {
+    let font: Handle<Font> = asset_server.load("fonts/terminal-grotesque.ttf");
+    commands.spawn((
+        DespawnOnExit(GameStates::Game),
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(20.0),
+            left: Val::Px(20.0),
+            right: Val::Px(20.0),
+            justify_content: JustifyContent::SpaceBetween,
+            ..default()
+        },
+    )).with_children(|parent| {
+        // Player 1 is spawned on the right, so list the scores right to left.
+        for player in (1..=player_count.0).rev() {
+            parent.spawn((
+                Node {
+                    flex_direction: FlexDirection::Column,
+                    align_items: AlignItems::Center,
+                    ..default()
+                },
+                children![
+                    (
+                        Text::new(format!("P{}", player)),
+                        TextColor(Color::BLACK),
+                        TextFont {
+                            font: font.clone(),
+                            font_size: 32.,
+                            ..default()
+                        },
+                    ),
+                    (
+                        ScoreDisplay(player),
+                        Text::new(""),
+                        TextColor(Color::BLACK),
+                        TextFont {
+                            font: font.clone(),
+                            font_size: 128.,
+                            ..default()
+                        },
+                    ),
+                    (
+                        DamageDisplay(player),
+                        Text::new("0%"),
+                        TextColor(Color::BLACK),
+                        TextFont {
+                            font: font.clone(),
+                            font_size: 48.,
+                            ..default()
+                        },
+                    ),
+                ],
+            ));
+        }
+    });
+    commands.spawn((
+        DespawnOnExit(GameStates::Game),
+        ClockDisplay,
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(20.0),
+            width: percent(100),
+            ..default()
+        },
+        Text::new(""),
+        TextLayout::new(Justify::Center, LineBreak::WordBoundary),
+        TextColor(Color::BLACK),
+        TextFont {
+            font,
+            font_size: 64.,
+            ..default()
+        },
+    ));
+}
+
+fn menu_screen(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let font: Handle<Font> = asset_server.load("fonts/terminal-grotesque.ttf");
+    commands.spawn((
+        DespawnOnExit(GameStates::Menu),
+        MenuText,
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(100.0),
+            width: percent(100),
+            ..default()
+        },
+        Text::new(""),
+        TextLayout::new(Justify::Center, LineBreak::WordBoundary),
+        TextColor(Color::BLACK),
+        TextFont {
+            font,
+            font_size: 64.,
+            ..default()
+        },
+    ));
+}
+
+fn update_menu_text(player_count: Res<PlayerCount>,
+                    rules: Res<MatchRules>,
+                    mut query: Query<&mut Text, With<MenuText>>) {
+    for mut text in &mut query {
+        **text = format!("Players: < {} >\nRules: ^ {} v\n[Enter] Start  [O]nline",
+                         player_count.0, rules.win_condition);
+    }
+}
+
+fn character_select_screen(mut commands: Commands,
+                           player_count: Res<PlayerCount>,
+                           asset_server: Res<AssetServer>) {
+    let font: Handle<Font> = asset_server.load("fonts/terminal-grotesque.ttf");
+    commands.spawn((
+        DespawnOnExit(GameStates::CharacterSelect),
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(40.0),
+            width: percent(100),
+            flex_direction: FlexDirection::Column,
+            align_items: AlignItems::Center,
+            row_gap: Val::Px(40.0),
+            ..default()
+        },
+    )).with_children(|parent| {
+        parent.spawn((
+            Text::new("[Left]/[Right] Character  [Up]/[Down] Color  [Attack] Ready\n\
+                       [1]-[8] CPU  [Enter] Continue  [Esc] Back"),
+            TextLayout::new(Justify::Center, LineBreak::WordBoundary),
+            TextColor(Color::BLACK),
+            TextFont {
+                font: font.clone(),
+                font_size: 32.,
+                ..default()
+            },
+        ));
+        parent.spawn(Node {
+            flex_wrap: FlexWrap::Wrap,
+            justify_content: JustifyContent::Center,
+            column_gap: Val::Px(40.0),
+            row_gap: Val::Px(20.0),
+            ..default()
+        }).with_children(|row| {
+            for player in 1..=player_count.0 {
+                row.spawn((
+                    Node {
+                        flex_direction: FlexDirection::Column,
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    children![
+                        (
+                            Text::new(format!("P{}", player)),
+                            TextColor(Color::BLACK),
+                            TextFont {
+                                font: font.clone(),
+                                font_size: 32.,
+                                ..default()
+                            },
+                        ),
+                        (
+                            CharacterPortrait(player),
+                            ImageNode::default(),
+                            Node {
+                                width: Val::Px(96.0),
+                                height: Val::Px(96.0),
+                                ..default()
+                            },
+                        ),
+                        (
+                            CharacterPickText(player),
+                            Text::new(""),
+                            TextLayout::new(Justify::Center, LineBreak::WordBoundary),
+                            TextColor(Color::BLACK),
+                            TextFont {
+                                font: font.clone(),
+                                font_size: 32.,
+                                ..default()
+                            },
+                        ),
+                    ],
+                ));
+            }
+        });
+    });
+}
+
+fn update_character_select(selection: Res<CharacterSelection>,
+                           registry: Res<CharacterRegistry>,
+                           player_count: Res<PlayerCount>,
+                           cpu_players: Res<CpuPlayers>,
+                           asset_server: Res<AssetServer>,
+                           mut portraits: Query<(&mut ImageNode, &CharacterPortrait)>,
+                           mut texts: Query<(&mut Text, &CharacterPickText)>) {
+    let palettes = selection.palettes(&registry, player_count.0);
+    for (mut image, portrait) in &mut portraits {
+        let Some(palette) = palettes.get(portrait.0 as usize - 1) else {
+            continue;
+        };
+        let character = registry.get(selection.pick(portrait.0).fighter);
+        image.image = asset_server.load(&character.sprite);
+        image.color = character.palette(*palette);
+    }
+    for (mut text, pick_text) in &mut texts {
+        let pick = selection.pick(pick_text.0);
+        let status = match cpu_players.0.get(&pick_text.0) {
+            Some(difficulty) => format!("CPU {}", difficulty),
+            None if pick.ready => "Ready!".to_string(),
+            None => String::new(),
+        };
+        text.set_if_neq(Text(format!("< {} >\n{}", registry.name(pick.fighter), status)));
+    }
+}
+
+fn stage_select_screen(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let font: Handle<Font> = asset_server.load("fonts/terminal-grotesque.ttf");
+    commands.spawn((
+        DespawnOnExit(GameStates::StageSelect),
+        StageSelectText,
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(100.0),
+            width: percent(100),
+            ..default()
+        },
+        Text::new(""),
+        TextLayout::new(Justify::Center, LineBreak::WordBoundary),
+        TextColor(Color::BLACK),
+        TextFont {
+            font,
+            font_size: 64.,
+            ..default()
+        },
+    ));
+}
+
+fn update_stage_select_text(choice: Res<StageChoice>,
+                            stage_list: Res<StageList>,
+                            mut query: Query<&mut Text, With<StageSelectText>>) {
+    let name = match *choice {
+        StageChoice::Stage(index) => stage_list.stages[index].name.as_str(),
+        StageChoice::Random => "Random",
+    };
+    for mut text in &mut query {
+        **text = format!("Stage: < {} >\n[Enter] Fight\n[Esc] Back", name);
+    }
+}
+
+fn lobby_screen(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let font: Handle<Font> = asset_server.load("fonts/terminal-grotesque.ttf");
+    commands.spawn((
+        DespawnOnExit(GameStates::Lobby),
+        LobbyText,
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(40.0),
+            width: percent(100),
+            ..default()
+        },
+        Text::new(""),
+        TextLayout::new(Justify::Center, LineBreak::WordBoundary),
+        TextColor(Color::BLACK),
+        TextFont {
+            font,
+            font_size: 40.,
+            ..default()
+        },
+    ));
+}
+
+fn update_lobby_text(client: Option<Res<LobbyClient>>,
+                     registry: Res<CharacterRegistry>,
+                     mut query: Query<&mut Text, With<LobbyText>>) {
+    let Some(client) = client else {
+        return;
+    };
+    let mut lines = Vec::new();
+    match &*client {
+        LobbyClient { lobby: Some((lobby, player)), .. } => {
+            lines.push(format!("Lobby {}: {} on {}", lobby.id, lobby.win_condition, lobby.stage));
+            for (i, member) in lobby.members.iter().enumerate() {
+                let you = if i as u32 + 1 == *player { " (you)" } else { "" };
+                let status = if member.ready { "  Ready!" } else { "" };
+                lines.push(format!("P{}{}: {} color {}{}", i + 1, you,
+                                   registry.name(Fighter(member.fighter)), member.palette + 1, status));
+            }
+            if lobby.members.len() < LOBBY_SIZE {
+                lines.push("Waiting for another player...".to_string());
+            }
+            let stage = if *player == 1 { "  [S]tage" } else { "" };
+            lines.push(format!("\n[Left]/[Right] Character  [Up]/[Down] Color  [Attack] Ready{}  [Esc] Leave",
+                               stage));
+        }
+        client => {
+            lines.push(format!("Lobbies on {}", client.server));
+            for (i, lobby) in client.lobbies.iter().enumerate() {
+                let cursor = if i == client.selected { ">" } else { " " };
+                lines.push(format!("{} {}: {}/{} players, {} on {}", cursor, lobby.id, lobby.players,
+                                   LOBBY_SIZE, lobby.win_condition, lobby.stage));
+            }
+            if client.lobbies.is_empty() {
+                lines.push("No lobbies yet".to_string());
+            }
+            lines.push("\n[H]ost  [Up]/[Down] Pick  [Enter] Join  [Esc] Back".to_string());
+        }
+    }
+    if let Some(error) = &client.error {
+        lines.push(error.clone());
+    }
+    for mut text in &mut query {
+        text.set_if_neq(Text(lines.join("\n")));
+    }
+}
+
+fn stage_background(stage: Res<ActiveStage>, mut clear_color: ResMut<ClearColor>) {
+    clear_color.0 = rgb(stage.0.background.color);
+}
+
+fn default_background(mut clear_color: ResMut<ClearColor>) {
+    clear_color.0 = BACKGROUND_COLOR;
+}
+
+fn initialize(mut commands: Commands) {
+    commands.spawn(Camera2d);
+}
+
+pub struct UiPlugin;
+impl Plugin for UiPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ClearColor(BACKGROUND_COLOR));
+        app.add_systems(Startup, initialize);
+        app.add_systems(OnEnter(GameStates::Game), (spawn_score_display, stage_background));
+        app.add_systems(OnExit(GameStates::Game), default_background);
+        app.add_systems(OnEnter(GameStates::Menu), menu_screen);
+        app.add_systems(Update, update_menu_text.run_if(in_state(GameStates::Menu)));
+        app.add_systems(OnEnter(GameStates::CharacterSelect), character_select_screen);
+        app.add_systems(Update, update_character_select
+                        .run_if(in_state(GameStates::CharacterSelect)));
+        app.add_systems(OnEnter(GameStates::StageSelect), stage_select_screen);
+        app.add_systems(Update, update_stage_select_text.run_if(in_state(GameStates::StageSelect)));
+        app.add_systems(OnEnter(GameStates::Lobby), lobby_screen);
+        app.add_systems(Update, update_lobby_text.run_if(in_state(GameStates::Lobby)));
+        app.add_systems(Update, (skin_players, tint_players, show_damage));
+        app.add_systems(Update, (show_score, show_clock).run_if(in_state(GameStates::Game)));
+        app.add_systems(FixedUpdate, flip_sprite
+                        .after(GameSystems::Integrate)
+                        .in_set(GameSet));
+        app.add_systems(OnEnter(GameStates::GameOver), game_over_screen);
+        app.add_systems(Update, show_replay_status.run_if(in_state(GameStates::GameOver)));
+        app.add_systems(OnEnter(PauseState::Paused), pause_screen);
+    }
+}